@@ -0,0 +1,14 @@
+create table sessions (
+    id uuid default uuid_generate_v4() primary key,
+    user_id uuid not null,
+    refresh_token_hash varchar not null unique,
+    user_agent varchar null,
+    ip varchar null,
+    created_at timestamp not null default current_timestamp,
+    last_seen_at timestamp not null default current_timestamp,
+    expires_at timestamp not null,
+    revoked_at timestamp null,
+    foreign key (user_id) references users(id)
+);
+
+create index sessions_user_id_idx on sessions (user_id);
@@ -0,0 +1,12 @@
+create table user_tokens (
+    id uuid default uuid_generate_v4() primary key,
+    user_id uuid not null,
+    purpose varchar not null,
+    token_hash varchar not null unique,
+    expires_at timestamp not null,
+    used_at timestamp null,
+    created_at timestamp not null default current_timestamp,
+    foreign key (user_id) references users(id)
+);
+
+create index user_tokens_user_id_purpose_idx on user_tokens (user_id, purpose);
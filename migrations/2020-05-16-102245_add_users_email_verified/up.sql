@@ -0,0 +1,3 @@
+-- Accounts created before verification existed are considered verified
+alter table users add column email_verified boolean not null default true;
+alter table users alter column email_verified set default false;
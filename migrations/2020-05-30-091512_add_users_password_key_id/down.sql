@@ -0,0 +1 @@
+alter table users drop column password_key_id;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Existing hashes were made with SERVER__SECRET_KEY, registered as the `default` key
+alter table users add column password_key_id varchar not null default 'default';
+alter table users alter column password_key_id drop default;
@@ -0,0 +1,5 @@
+alter table users add column role varchar not null default 'reader'
+    check (role in ('reader', 'author', 'moderator', 'admin'));
+
+-- Everyone could post before roles existed
+update users set role = 'author';
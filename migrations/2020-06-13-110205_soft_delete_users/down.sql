@@ -0,0 +1,16 @@
+alter table user_tokens drop constraint user_tokens_user_id_fkey,
+    add foreign key (user_id) references users(id);
+
+alter table sessions drop constraint sessions_user_id_fkey,
+    add foreign key (user_id) references users(id);
+
+alter table comments drop constraint comments_post_id_fkey,
+    add foreign key (post_id) references posts(id);
+
+alter table comments drop constraint comments_author_id_fkey,
+    add foreign key (author_id) references users(id);
+
+alter table posts drop constraint posts_author_id_fkey,
+    add foreign key (author_id) references users(id);
+
+alter table users drop column deleted_at;
@@ -0,0 +1,17 @@
+alter table users add column deleted_at timestamp null;
+
+-- Purging a deleted account removes everything that belongs to it
+alter table posts drop constraint posts_author_id_fkey,
+    add foreign key (author_id) references users(id) on delete cascade;
+
+alter table comments drop constraint comments_author_id_fkey,
+    add foreign key (author_id) references users(id) on delete cascade;
+
+alter table comments drop constraint comments_post_id_fkey,
+    add foreign key (post_id) references posts(id) on delete cascade;
+
+alter table sessions drop constraint sessions_user_id_fkey,
+    add foreign key (user_id) references users(id) on delete cascade;
+
+alter table user_tokens drop constraint user_tokens_user_id_fkey,
+    add foreign key (user_id) references users(id) on delete cascade;
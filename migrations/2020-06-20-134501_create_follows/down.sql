@@ -0,0 +1 @@
+drop table if exists follows;
\ No newline at end of file
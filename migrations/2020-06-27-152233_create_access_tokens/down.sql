@@ -0,0 +1 @@
+drop table if exists access_tokens;
\ No newline at end of file
@@ -0,0 +1,14 @@
+create table access_tokens (
+    id uuid default uuid_generate_v4() primary key,
+    user_id uuid not null,
+    name varchar not null,
+    scopes varchar[] not null,
+    token_hash varchar not null unique,
+    created_at timestamp not null default current_timestamp,
+    last_used_at timestamp null,
+    expires_at timestamp null,
+    revoked_at timestamp null,
+    foreign key (user_id) references users(id) on delete cascade
+);
+
+create index access_tokens_user_id_idx on access_tokens (user_id);
@@ -0,0 +1,6 @@
+create table login_attempts (
+    key varchar primary key,
+    failures integer not null default 0,
+    last_failed_at timestamp not null default current_timestamp,
+    locked_until timestamp null
+);
@@ -0,0 +1,5 @@
+drop index users_email_lower_key;
+drop index users_username_lower_key;
+
+alter table users add constraint users_email_key unique (email);
+alter table users add constraint users_username_key unique (username);
@@ -0,0 +1,26 @@
+-- Accounts whose usernames or emails only differ by case have to be merged or renamed first,
+-- list them all so they can be fixed in one go
+do $$
+declare
+    collisions text;
+begin
+    select string_agg(collision, '; ') into collisions from (
+        select 'username ' || string_agg(username, ', ' order by created_at) as collision
+        from users group by lower(username) having count(*) > 1
+        union all
+        select 'email ' || string_agg(email, ', ' order by created_at) as collision
+        from users group by lower(email) having count(*) > 1
+    ) as duplicates;
+
+    if collisions is not null then
+        raise exception 'Case-insensitive login collisions: %', collisions
+            using hint = 'Rename or merge these accounts, then run the migration again';
+    end if;
+end
+$$;
+
+alter table users drop constraint users_username_key;
+alter table users drop constraint users_email_key;
+
+create unique index users_username_lower_key on users (lower(username));
+create unique index users_email_lower_key on users (lower(email));
@@ -0,0 +1,4 @@
+drop index comments_parent_id_idx;
+
+alter table comments drop column depth,
+    drop column parent_id;
@@ -0,0 +1,5 @@
+-- Deleting a comment removes its whole thread
+alter table comments add column parent_id uuid null references comments(id) on delete cascade,
+    add column depth integer not null default 0;
+
+create index comments_parent_id_idx on comments (parent_id);
@@ -0,0 +1,3 @@
+drop trigger if exists posts_slug_history on posts;
+drop function if exists record_post_slug_change();
+drop table if exists post_slug_history;
@@ -0,0 +1,26 @@
+-- Slugs a post was published under before, so old links keep working
+create table post_slug_history (
+    slug varchar primary key,
+    post_id uuid not null,
+    created_at timestamp not null default current_timestamp,
+    foreign key (post_id) references posts(id) on delete cascade
+);
+
+create index post_slug_history_post_id_idx on post_slug_history (post_id);
+
+-- A slug in use by a post always wins over history, whichever post it belonged to
+create function record_post_slug_change() returns trigger as $$
+begin
+    delete from post_slug_history where slug = new.slug;
+
+    if tg_op = 'UPDATE' and old.slug <> new.slug then
+        insert into post_slug_history (slug, post_id) values (old.slug, new.id)
+            on conflict (slug) do update set post_id = excluded.post_id, created_at = current_timestamp;
+    end if;
+
+    return new;
+end
+$$ language plpgsql;
+
+create trigger posts_slug_history after insert or update of slug on posts
+    for each row execute procedure record_post_slug_change();
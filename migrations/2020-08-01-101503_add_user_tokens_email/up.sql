@@ -0,0 +1,6 @@
+-- The address an email verification token was sent to, it only verifies that address.
+-- Outstanding verification tokens don't have one, users can ask for a new email.
+alter table user_tokens add column email varchar null;
+
+update user_tokens set used_at = current_timestamp
+    where purpose = 'email_verification' and used_at is null;
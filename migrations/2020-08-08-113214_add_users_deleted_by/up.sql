@@ -0,0 +1,3 @@
+-- Who deleted the account, only accounts deleted by their owner can be restored by them.
+-- Earlier deletions are left unknown and can't be self-restored.
+alter table users add column deleted_by uuid null;
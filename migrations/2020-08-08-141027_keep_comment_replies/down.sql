@@ -0,0 +1,2 @@
+alter table comments drop constraint comments_parent_id_fkey,
+    add foreign key (parent_id) references comments(id) on delete cascade;
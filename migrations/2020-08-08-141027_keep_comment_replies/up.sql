@@ -0,0 +1,3 @@
+-- Deleting a comment keeps the replies, they move up to the top level
+alter table comments drop constraint comments_parent_id_fkey,
+    add foreign key (parent_id) references comments(id) on delete set null;
@@ -0,0 +1,12 @@
+create or replace function record_post_slug_change() returns trigger as $$
+begin
+    delete from post_slug_history where slug = new.slug;
+
+    if tg_op = 'UPDATE' and old.slug <> new.slug then
+        insert into post_slug_history (slug, post_id) values (old.slug, new.id)
+            on conflict (slug) do update set post_id = excluded.post_id, created_at = current_timestamp;
+    end if;
+
+    return new;
+end
+$$ language plpgsql;
@@ -0,0 +1,20 @@
+-- Old slugs stay with their post, taking one that belongs to another post is a unique violation
+create or replace function record_post_slug_change() returns trigger as $$
+begin
+    if exists (select 1 from post_slug_history where slug = new.slug and post_id <> new.id) then
+        raise exception using
+            errcode = 'unique_violation',
+            message = format('Slug %s is still used by another post', new.slug);
+    end if;
+
+    -- Going back to one of its own old slugs
+    delete from post_slug_history where slug = new.slug;
+
+    if tg_op = 'UPDATE' and old.slug <> new.slug then
+        insert into post_slug_history (slug, post_id) values (old.slug, new.id)
+            on conflict (slug) do update set post_id = excluded.post_id, created_at = current_timestamp;
+    end if;
+
+    return new;
+end
+$$ language plpgsql;
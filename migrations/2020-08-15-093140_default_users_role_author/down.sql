@@ -0,0 +1 @@
+alter table users alter column role set default 'reader';
@@ -0,0 +1,2 @@
+-- New accounts can post once their email is verified, readers are accounts an admin restricted
+alter table users alter column role set default 'author';
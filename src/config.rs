@@ -1,28 +1,170 @@
 pub use config::ConfigError;
 use deadpool_postgres::Pool;
 use dotenv::dotenv;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use slog::{o, Drain};
 use slog_async;
 use slog_envlogger;
 use slog_term;
 use tokio_postgres::NoTls;
 use crate::errors::{AppError, AppErrorType};
-use argonautica::Hasher;
+use argonautica::{Hasher, Verifier};
 use futures::compat::Future01CompatExt;
+use futures_cpupool::CpuPool;
+use chrono::Utc;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use uuid::Uuid;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, net::IpAddr, path::PathBuf, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}};
+use crate::mailer::{FileMailer, Mailer, Notifier, SmtpMailer, StdoutMailer};
 
 #[derive(Deserialize)]
 pub struct ServerConfig {
     pub host: String,
     pub port: i32,
     pub url: String,
-    pub secret_key: String,
+    /// Legacy single argon2 secret, registered under the `default` key id
+    pub secret_key: Option<String>,
+    /// Argon2 secrets by key id, set as `SERVER__SECRET_KEYS__<ID>`. Key ids are lowercased.
+    #[serde(default)]
+    pub secret_keys: HashMap<String, String>,
+    /// Key id used for new hashes
+    #[serde(default = "default_secret_key_id")]
+    pub active_secret_key: String,
+    pub jwt_secret: String,
+    #[serde(default = "default_access_token_ttl")]
+    pub access_token_ttl: i64,
+    #[serde(default = "default_refresh_token_ttl")]
+    pub refresh_token_ttl: i64,
+    #[serde(default = "default_password_reset_ttl")]
+    pub password_reset_ttl: i64,
+    #[serde(default = "default_email_verification_ttl")]
+    pub email_verification_ttl: i64,
+    #[serde(default = "default_hash_iterations")]
+    pub hash_iterations: u32,
+    /// Memory used by each hash, in KiB
+    #[serde(default = "default_hash_memory_size")]
+    pub hash_memory_size: u32,
+    #[serde(default = "default_hash_lanes")]
+    pub hash_lanes: u32,
+    /// Hashes running at once, each one runs on a single thread so this is also the thread count
+    #[serde(default = "default_max_concurrent_hashes")]
+    pub max_concurrent_hashes: usize,
+    /// Hashes waiting for a worker before new ones are rejected
+    #[serde(default = "default_max_queued_hashes")]
+    pub max_queued_hashes: usize,
+    /// Seconds a deleted account can be restored before it's purged
+    #[serde(default = "default_account_deletion_grace_period")]
+    pub account_deletion_grace_period: i64,
+    /// How deep comment replies can nest, top level comments are at depth 0
+    #[serde(default = "default_max_comment_depth")]
+    pub max_comment_depth: i32,
+    /// Comma separated proxy IPs whose `X-Forwarded-For` header is believed
+    #[serde(default)]
+    pub trusted_proxies: String,
+}
+
+pub const DEFAULT_SECRET_KEY_ID: &str = "default";
+
+fn default_secret_key_id() -> String {
+    DEFAULT_SECRET_KEY_ID.to_string()
+}
+
+fn default_access_token_ttl() -> i64 {
+    15 * 60
+}
+
+fn default_refresh_token_ttl() -> i64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_password_reset_ttl() -> i64 {
+    60 * 60
+}
+
+fn default_email_verification_ttl() -> i64 {
+    2 * 24 * 60 * 60
+}
+
+fn default_hash_iterations() -> u32 {
+    HashParams::default().iterations
+}
+
+fn default_hash_memory_size() -> u32 {
+    HashParams::default().memory_size
+}
+
+fn default_hash_lanes() -> u32 {
+    HashParams::default().lanes
+}
+
+fn default_max_concurrent_hashes() -> usize {
+    4
+}
+
+fn default_max_queued_hashes() -> usize {
+    64
+}
+
+fn default_max_comment_depth() -> i32 {
+    5
+}
+
+fn default_account_deletion_grace_period() -> i64 {
+    30 * 24 * 60 * 60
+}
+
+#[derive(Deserialize)]
+pub struct MailerConfig {
+    /// One of `stdout`, `file` or `smtp`
+    #[serde(default = "default_mailer_kind")]
+    pub kind: String,
+    #[serde(default = "default_mailer_from")]
+    pub from: String,
+    pub path: Option<String>,
+    pub smtp_host: Option<String>,
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    #[serde(default)]
+    pub smtp_tls: bool,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+}
+
+fn default_mailer_kind() -> String {
+    "stdout".to_string()
+}
+
+fn default_mailer_from() -> String {
+    "no-reply@localhost".to_string()
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+impl Default for MailerConfig {
+    fn default() -> Self {
+        MailerConfig {
+            kind: default_mailer_kind(),
+            from: default_mailer_from(),
+            path: None,
+            smtp_host: None,
+            smtp_port: default_smtp_port(),
+            smtp_tls: false,
+            smtp_username: None,
+            smtp_password: None,
+        }
+    }
 }
 
 #[derive(Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub pg: deadpool_postgres::Config,
+    #[serde(default)]
+    pub mailer: MailerConfig,
 }
 
 impl Config {
@@ -43,10 +185,105 @@ impl Config {
         self.pg.create_pool(NoTls).unwrap()
     }
 
-    pub fn hashing_service(&self) -> HashingService {
-        HashingService {
-            secret_key: self.server.secret_key.clone()
+    pub fn hashing_service(&self) -> Result<HashingService, ConfigError> {
+        let mut secret_keys = self.server.secret_keys.clone();
+
+        if let Some(secret_key) = &self.server.secret_key {
+            secret_keys
+                .entry(DEFAULT_SECRET_KEY_ID.to_string())
+                .or_insert_with(|| secret_key.clone());
         }
+
+        if !secret_keys.contains_key(&self.server.active_secret_key) {
+            return Err(ConfigError::Message(format!(
+                "Active secret key {} is not configured",
+                self.server.active_secret_key
+            )));
+        }
+
+        if self.server.max_concurrent_hashes < 1 {
+            return Err(ConfigError::Message(
+                "SERVER__MAX_CONCURRENT_HASHES must be at least 1".to_string()
+            ));
+        }
+
+        Ok(HashingService::new(
+            secret_keys,
+            self.server.active_secret_key.clone(),
+            HashParams {
+                iterations: self.server.hash_iterations,
+                memory_size: self.server.hash_memory_size,
+                lanes: self.server.hash_lanes,
+            },
+            self.server.max_concurrent_hashes,
+            self.server.max_queued_hashes,
+        ))
+    }
+
+    pub fn token_service(&self) -> TokenService {
+        TokenService {
+            secret: self.server.jwt_secret.clone(),
+            access_token_ttl: self.server.access_token_ttl,
+            refresh_token_ttl: self.server.refresh_token_ttl,
+            password_reset_ttl: self.server.password_reset_ttl,
+            email_verification_ttl: self.server.email_verification_ttl,
+        }
+    }
+
+    pub fn comment_settings(&self) -> CommentSettings {
+        CommentSettings {
+            max_depth: self.server.max_comment_depth,
+        }
+    }
+
+    pub fn trusted_proxies(&self) -> Result<TrustedProxies, ConfigError> {
+        let proxies = self.server.trusted_proxies
+            .split(',')
+            .map(|proxy| proxy.trim())
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| {
+                proxy.parse::<IpAddr>().map_err(|err| {
+                    ConfigError::Message(format!("Invalid trusted proxy {}. {}", proxy, err))
+                })
+            })
+            .collect::<Result<Vec<IpAddr>, _>>()?;
+
+        Ok(TrustedProxies(Arc::new(proxies)))
+    }
+
+    pub fn account_settings(&self) -> AccountSettings {
+        AccountSettings {
+            deletion_grace_period: self.server.account_deletion_grace_period,
+        }
+    }
+
+    pub fn notifier(&self) -> Result<Notifier, ConfigError> {
+        let from = self.mailer.from.clone();
+
+        let mailer: Arc<dyn Mailer> = match self.mailer.kind.as_str() {
+            "stdout" => Arc::new(StdoutMailer { from }),
+            "file" => Arc::new(FileMailer {
+                from,
+                path: self.mailer.path.clone().map(PathBuf::from).ok_or_else(|| {
+                    ConfigError::Message("MAILER__PATH is required by the file mailer".to_string())
+                })?,
+            }),
+            "smtp" => Arc::new(SmtpMailer {
+                from,
+                host: self.mailer.smtp_host.clone().ok_or_else(|| {
+                    ConfigError::Message("MAILER__SMTP_HOST is required by the smtp mailer".to_string())
+                })?,
+                port: self.mailer.smtp_port,
+                tls: self.mailer.smtp_tls,
+                credentials: match (&self.mailer.smtp_username, &self.mailer.smtp_password) {
+                    (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+                    _ => None,
+                },
+            }),
+            kind => return Err(ConfigError::Message(format!("Unknown mailer kind {}", kind))),
+        };
+
+        Ok(Notifier::new(mailer, self.server.url.clone()))
     }
 
     fn configure_log() {
@@ -60,25 +297,523 @@ impl Config {
     }
 }
 
+#[derive(Clone, Copy)]
+pub struct CommentSettings {
+    pub max_depth: i32,
+}
+
+/// Proxies in front of the app, the only peers allowed to say who the client is
+#[derive(Clone, Default)]
+pub struct TrustedProxies(Arc<Vec<IpAddr>>);
+
+impl TrustedProxies {
+    /// The client's IP. Forwarded addresses are only read when the peer is a trusted proxy,
+    /// walking back from the nearest hop until an address that isn't one of ours.
+    pub fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        if !self.0.contains(&peer) {
+            return peer;
+        }
+
+        let mut client = peer;
+
+        for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => client = ip,
+                Err(_) => break,
+            }
+
+            if !self.0.contains(&client) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct AccountSettings {
+    /// Seconds a deleted account can be restored before it's purged
+    pub deletion_grace_period: i64,
+}
+
+/// Argon2 cost parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashParams {
+    pub iterations: u32,
+    pub memory_size: u32,
+    pub lanes: u32,
+}
+
+impl Default for HashParams {
+    /// argonautica's defaults, with a fixed lane count so hashes don't depend on the host's CPUs
+    fn default() -> Self {
+        HashParams {
+            iterations: 192,
+            memory_size: 4096,
+            lanes: 4,
+        }
+    }
+}
+
+impl HashParams {
+    /// Reads the parameters of an encoded hash like `$argon2id$v=19$m=4096,t=192,p=4$salt$hash`
+    pub fn from_hash(hash: &str) -> Option<HashParams> {
+        let encoded = hash.split('$').nth(3)?;
+
+        let mut memory_size = None;
+        let mut iterations = None;
+        let mut lanes = None;
+
+        for param in encoded.split(',') {
+            let mut pair = param.splitn(2, '=');
+            let value = pair.nth(1).and_then(|value| value.parse::<u32>().ok());
+
+            match param.chars().next() {
+                Some('m') => memory_size = value,
+                Some('t') => iterations = value,
+                Some('p') => lanes = value,
+                _ => {}
+            }
+        }
+
+        Some(HashParams {
+            iterations: iterations?,
+            memory_size: memory_size?,
+            lanes: lanes?,
+        })
+    }
+}
+
+/// Slot taken by a hash that is running or waiting for a worker
+struct PendingHash(Arc<AtomicUsize>);
+
+impl Drop for PendingHash {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A stored password hash and the id of the secret key it was made with
+pub struct PasswordHash {
+    pub hash: String,
+    pub key_id: String,
+}
+
+/// Hashes and verifies passwords on a bounded worker pool,
+/// so bursts of signups and logins can't take every core.
+/// Each hash runs on one pool thread, so at most `max_concurrent` threads are busy hashing.
+/// New hashes use the active secret key, retired keys are kept to verify older hashes.
 #[derive(Clone)]
 pub struct HashingService {
-    secret_key: String
+    secret_keys: Arc<HashMap<String, String>>,
+    active_key_id: String,
+    params: HashParams,
+    cpu_pool: CpuPool,
+    pending: Arc<AtomicUsize>,
+    max_pending: usize,
+    /// Throwaway hash made on first use, checked when a login doesn't match any account
+    dummy_hash: Arc<Mutex<Option<String>>>,
 }
 
 impl HashingService {
-    pub async fn hash(&self, password: String) -> Result<String, AppError> {
-        Hasher::default()
+    pub fn new(
+        secret_keys: HashMap<String, String>,
+        active_key_id: String,
+        params: HashParams,
+        max_concurrent: usize,
+        max_queued: usize,
+    ) -> HashingService {
+        HashingService {
+            secret_keys: Arc::new(secret_keys),
+            active_key_id,
+            params,
+            cpu_pool: CpuPool::new(max_concurrent),
+            pending: Arc::new(AtomicUsize::new(0)),
+            max_pending: max_concurrent + max_queued,
+            dummy_hash: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn reserve(&self) -> Result<PendingHash, AppError> {
+        let pending = self.pending.fetch_add(1, Ordering::SeqCst);
+        let slot = PendingHash(self.pending.clone());
+
+        if pending >= self.max_pending {
+            return Err(AppError {
+                message: Some("Server is busy, try again later".to_string()),
+                cause: Some(format!("{} hashes pending", pending)),
+                error_type: AppErrorType::RateLimited,
+                field: None
+            });
+        }
+
+        Ok(slot)
+    }
+
+    fn secret_key(&self, key_id: &str) -> Result<&str, AppError> {
+        self.secret_keys
+            .get(key_id)
+            .map(|secret_key| secret_key.as_str())
+            .ok_or(AppError {
+                message: None,
+                cause: Some(format!("Unknown secret key id {}", key_id)),
+                error_type: AppErrorType::HashingError,
+                field: None
+            })
+    }
+
+    pub async fn hash(&self, password: String) -> Result<PasswordHash, AppError> {
+        let secret_key = self.secret_key(&self.active_key_id)?;
+        let _slot = self.reserve()?;
+
+        let hash = Hasher::default()
+            .configure_iterations(self.params.iterations)
+            .configure_memory_size(self.params.memory_size)
+            .configure_lanes(self.params.lanes)
+            .configure_threads(1)
+            .configure_cpu_pool(self.cpu_pool.clone())
             .with_password(&password)
-            .with_secret_key(&self.secret_key)
+            .with_secret_key(secret_key)
             .hash_non_blocking()
             .compat()
             .await
             .map_err(|err| {
                 AppError {
-                    message: Some("Invalid password provided".to_string()),
+                    message: None,
+                    cause: Some(err.to_string()),
+                    error_type: AppErrorType::HashingError,
+                    field: None
+                }
+            })?;
+
+        Ok(PasswordHash {
+            hash,
+            key_id: self.active_key_id.clone(),
+        })
+    }
+
+    pub async fn verify(&self, password: String, hash: String, key_id: &str) -> Result<bool, AppError> {
+        let secret_key = self.secret_key(key_id)?;
+        let _slot = self.reserve()?;
+
+        Verifier::default()
+            .configure_threads(1)
+            .configure_cpu_pool(self.cpu_pool.clone())
+            .with_hash(&hash)
+            .with_password(&password)
+            .with_secret_key(secret_key)
+            .verify_non_blocking()
+            .compat()
+            .await
+            .map_err(|err| {
+                AppError {
+                    message: None,
+                    cause: Some(err.to_string()),
+                    error_type: AppErrorType::HashingError,
+                    field: None
+                }
+            })
+    }
+
+    /// Verifies the password against a throwaway hash made with the current parameters,
+    /// so a login for an unknown account takes as long as a wrong password
+    pub async fn verify_dummy(&self, password: String) -> Result<bool, AppError> {
+        let cached = self.dummy_hash.lock().unwrap().clone();
+
+        let hash = match cached {
+            Some(hash) => hash,
+            None => {
+                let hash = self.hash(TokenService::opaque_token()).await?.hash;
+                *self.dummy_hash.lock().unwrap() = Some(hash.clone());
+                hash
+            }
+        };
+
+        self.verify(password, hash, &self.active_key_id).await
+    }
+
+    /// Whether a stored hash was made with other parameters or another key than the current ones
+    pub fn needs_rehash(&self, hash: &str, key_id: &str) -> bool {
+        key_id != self.active_key_id || HashParams::from_hash(hash) != Some(self.params)
+    }
+}
+
+/// Claims carried by the access tokens handed out on login
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: Uuid,
+    pub sid: Uuid,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+#[derive(Clone)]
+pub struct TokenService {
+    secret: String,
+    access_token_ttl: i64,
+    refresh_token_ttl: i64,
+    password_reset_ttl: i64,
+    email_verification_ttl: i64,
+}
+
+impl TokenService {
+    pub fn refresh_token_ttl(&self) -> i64 {
+        self.refresh_token_ttl
+    }
+
+    pub fn password_reset_ttl(&self) -> i64 {
+        self.password_reset_ttl
+    }
+
+    pub fn email_verification_ttl(&self) -> i64 {
+        self.email_verification_ttl
+    }
+
+    pub fn access_token(&self, user_id: Uuid, session_id: Uuid) -> Result<String, AppError> {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            sub: user_id,
+            sid: session_id,
+            iat: now,
+            exp: now + self.access_token_ttl,
+        };
+
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(self.secret.as_ref()))
+            .map_err(|err| {
+                AppError {
+                    message: Some("Error signing access token.".to_string()),
                     cause: Some(err.to_string()),
-                    error_type: AppErrorType::InvalidField
+                    error_type: AppErrorType::DbError,
+                    field: None
                 }
             })
     }
-}
\ No newline at end of file
+
+    pub fn decode(&self, token: &str) -> Result<Claims, AppError> {
+        decode::<Claims>(token, &DecodingKey::from_secret(self.secret.as_ref()), &Validation::default())
+            .map(|data| data.claims)
+            .map_err(|err| {
+                AppError {
+                    message: Some("Invalid or expired access token".to_string()),
+                    cause: Some(err.to_string()),
+                    error_type: AppErrorType::Unauthorized,
+                    field: None
+                }
+            })
+    }
+
+    /// Random opaque token, only its digest should be persisted
+    pub fn opaque_token() -> String {
+        thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(48)
+            .collect()
+    }
+
+    pub fn digest(token: &str) -> String {
+        hex::encode(Sha256::digest(token.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{HashParams, HashingService, TokenService, TrustedProxies};
+    use std::{collections::HashMap, net::IpAddr, sync::Arc};
+    use uuid::Uuid;
+
+    fn hashing_service(max_concurrent: usize, max_queued: usize) -> HashingService {
+        let mut secret_keys = HashMap::new();
+        secret_keys.insert("old".to_string(), "old-secret".to_string());
+        secret_keys.insert("new".to_string(), "new-secret".to_string());
+
+        HashingService::new(secret_keys, "new".to_string(), HashParams::default(), max_concurrent, max_queued)
+    }
+
+    fn token_service() -> TokenService {
+        TokenService {
+            secret: "test-secret".to_string(),
+            access_token_ttl: 60,
+            refresh_token_ttl: 60,
+            password_reset_ttl: 60,
+            email_verification_ttl: 60,
+        }
+    }
+
+    #[test]
+    fn test_access_token_round_trip() {
+        let tokens = token_service();
+        let user_id = Uuid::new_v4();
+        let session_id = Uuid::new_v4();
+
+        let token = tokens.access_token(user_id, session_id).unwrap();
+        let claims = tokens.decode(&token).unwrap();
+
+        assert_eq!(claims.sub, user_id, "Token subject should be the user id");
+        assert_eq!(claims.sid, session_id, "Token should carry the session id");
+    }
+
+    #[test]
+    fn test_access_token_wrong_secret() {
+        let token = token_service().access_token(Uuid::new_v4(), Uuid::new_v4()).unwrap();
+
+        let other = TokenService {
+            secret: "other-secret".to_string(),
+            access_token_ttl: 60,
+            refresh_token_ttl: 60,
+            password_reset_ttl: 60,
+            email_verification_ttl: 60,
+        };
+
+        assert!(other.decode(&token).is_err(), "Token signed with another secret should be rejected");
+    }
+
+    #[test]
+    fn test_expired_access_token() {
+        let tokens = TokenService {
+            secret: "test-secret".to_string(),
+            access_token_ttl: -3600,
+            refresh_token_ttl: 60,
+            password_reset_ttl: 60,
+            email_verification_ttl: 60,
+        };
+
+        let token = tokens.access_token(Uuid::new_v4(), Uuid::new_v4()).unwrap();
+
+        assert!(tokens.decode(&token).is_err(), "Expired token should be rejected");
+    }
+
+    #[test]
+    fn test_opaque_token_digest() {
+        let token = TokenService::opaque_token();
+
+        assert_eq!(token.len(), 48, "Opaque tokens should be 48 characters long");
+        assert_ne!(token, TokenService::opaque_token(), "Opaque tokens should be random");
+        assert_eq!(
+            TokenService::digest(&token),
+            TokenService::digest(&token),
+            "Digest should be deterministic"
+        );
+        assert_ne!(TokenService::digest(&token), token, "Digest should not be the token itself");
+    }
+
+    #[test]
+    fn test_hash_params_from_hash() {
+        let params = HashParams::from_hash("$argon2id$v=19$m=4096,t=192,p=4$c29tZXNhbHQ$aGFzaA");
+
+        assert_eq!(
+            params,
+            Some(HashParams { iterations: 192, memory_size: 4096, lanes: 4 }),
+            "Parameters should be read from the encoded hash"
+        );
+        assert_eq!(HashParams::from_hash("not a hash"), None, "Garbage should not parse");
+    }
+
+    #[test]
+    fn test_needs_rehash() {
+        let hashing = hashing_service(1, 0);
+
+        assert!(
+            !hashing.needs_rehash("$argon2id$v=19$m=4096,t=192,p=4$c29tZXNhbHQ$aGFzaA", "new"),
+            "Hash with the current parameters should be kept"
+        );
+        assert!(
+            hashing.needs_rehash("$argon2id$v=19$m=4096,t=128,p=4$c29tZXNhbHQ$aGFzaA", "new"),
+            "Hash with fewer iterations should be upgraded"
+        );
+        assert!(
+            hashing.needs_rehash("$argon2id$v=19$m=4096,t=192,p=4$c29tZXNhbHQ$aGFzaA", "old"),
+            "Hash made with a retired key should be moved to the active key"
+        );
+    }
+
+    #[test]
+    fn test_hashing_pool_saturated() {
+        let hashing = hashing_service(1, 1);
+
+        let first = hashing.reserve();
+        let second = hashing.reserve();
+
+        assert!(first.is_ok() && second.is_ok(), "Running and queued hashes should be accepted");
+        assert!(hashing.reserve().is_err(), "Hashes beyond the queue should be rejected");
+
+        drop(first);
+
+        assert!(hashing.reserve().is_ok(), "Finished hashes should free their slot");
+    }
+
+    #[actix_rt::test]
+    async fn test_verify_with_retired_key() {
+        let mut old_keys = HashMap::new();
+        old_keys.insert("old".to_string(), "old-secret".to_string());
+        let old_hashing = HashingService::new(old_keys, "old".to_string(), HashParams::default(), 1, 0);
+
+        let old_hash = old_hashing.hash("password".to_string()).await.unwrap();
+        let hashing = hashing_service(1, 0);
+
+        assert!(
+            hashing.verify("password".to_string(), old_hash.hash.clone(), &old_hash.key_id).await.unwrap(),
+            "Hash made with a retired key should still verify"
+        );
+        assert!(
+            !hashing.verify("password".to_string(), old_hash.hash, "new").await.unwrap(),
+            "Hash should not verify with another key"
+        );
+        assert_eq!(
+            hashing.hash("password".to_string()).await.unwrap().key_id,
+            "new",
+            "New hashes should use the active key"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_verify_dummy() {
+        let hashing = hashing_service(1, 0);
+
+        assert!(
+            !hashing.verify_dummy("password".to_string()).await.unwrap(),
+            "No password should match the dummy hash"
+        );
+        assert!(
+            !hashing.verify_dummy("password".to_string()).await.unwrap(),
+            "The cached dummy hash should be reused"
+        );
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_client_ip_ignores_untrusted_forwarding() {
+        let proxies = TrustedProxies(Arc::new(vec![ip("10.0.0.1")]));
+
+        assert_eq!(
+            proxies.client_ip(ip("203.0.113.7"), Some("198.51.100.1")),
+            ip("203.0.113.7"),
+            "Clients that aren't trusted proxies can't pick their IP"
+        );
+    }
+
+    #[test]
+    fn test_client_ip_through_proxies() {
+        let proxies = TrustedProxies(Arc::new(vec![ip("10.0.0.1"), ip("10.0.0.2")]));
+
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), Some("198.51.100.1, 203.0.113.7, 10.0.0.2")),
+            ip("203.0.113.7"),
+            "The nearest untrusted hop should be the client, earlier hops can be spoofed"
+        );
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), Some("garbage, 10.0.0.2")),
+            ip("10.0.0.2"),
+            "Unparseable hops should stop the walk"
+        );
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), None),
+            ip("10.0.0.1"),
+            "Without the header the proxy is the client"
+        );
+    }
+}
@@ -1,17 +1,50 @@
 use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
 use deadpool_postgres::PoolError;
 use serde::Serialize;
-use std::fmt;
-use tokio_postgres::error::Error;
+use std::{error::Error as _, fmt};
+use tokio_postgres::error::{DbError, Error, SqlState};
 use tokio_pg_mapper;
-use juniper::{IntoFieldError, FieldError, Value};
+use juniper::{IntoFieldError, FieldError, Object, Value};
+use slog_scope::{error, info};
+use uuid::Uuid;
 
-#[derive(Debug, Clone)]
+use crate::validation::Violation;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum AppErrorType {
     DbError,
+    /// Password hashing or verification failed on our side
+    HashingError,
     #[allow(dead_code)]
     NotFoundError,
-    InvalidField
+    InvalidField,
+    Unauthorized,
+    Forbidden,
+    RateLimited,
+    /// Clashes with existing data, e.g. a taken slug
+    Conflict,
+    /// The database gave up waiting on the query
+    Timeout,
+    /// Input that breaks one or more rules, each reported separately
+    ValidationError(Vec<Violation>),
+}
+
+impl AppErrorType {
+    /// Stable, machine-readable code sent to GraphQL clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppErrorType::DbError => "INTERNAL_ERROR",
+            AppErrorType::HashingError => "INTERNAL_ERROR",
+            AppErrorType::NotFoundError => "NOT_FOUND",
+            AppErrorType::InvalidField => "INVALID_FIELD",
+            AppErrorType::Unauthorized => "UNAUTHORIZED",
+            AppErrorType::Forbidden => "FORBIDDEN",
+            AppErrorType::RateLimited => "RATE_LIMITED",
+            AppErrorType::Conflict => "CONFLICT",
+            AppErrorType::Timeout => "TIMEOUT",
+            AppErrorType::ValidationError(_) => "VALIDATION_ERROR",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -19,6 +52,8 @@ pub struct AppError {
     pub message: Option<String>,
     pub cause: Option<String>,
     pub error_type: AppErrorType,
+    /// Input field the error is about, when a repository knows it
+    pub field: Option<String>,
 }
 
 impl AppError {
@@ -30,39 +65,171 @@ impl AppError {
             } => message.clone(),
             AppError {
                 error_type: AppErrorType::NotFoundError,
+                field: None,
                 ..
             } => "The requested item was not found".to_string(),
             AppError {
                 error_type: AppErrorType::InvalidField,
+                field: None,
                 ..
             } => "Invalid value provided".to_string(),
+            AppError {
+                error_type: AppErrorType::Unauthorized,
+                field: None,
+                ..
+            } => "Authentication required".to_string(),
+            AppError {
+                error_type: AppErrorType::Forbidden,
+                field: None,
+                ..
+            } => "You are not allowed to perform this action".to_string(),
+            AppError {
+                error_type: AppErrorType::RateLimited,
+                field: None,
+                ..
+            } => "Too many requests, try again later".to_string(),
+            AppError {
+                error_type: AppErrorType::Conflict,
+                field: None,
+                ..
+            } => "This conflicts with an existing item".to_string(),
+            AppError {
+                error_type: AppErrorType::Timeout,
+                field: None,
+                ..
+            } => "The request took too long, try again later".to_string(),
+            AppError {
+                error_type: AppErrorType::ValidationError(_),
+                field: None,
+                ..
+            } => "Invalid input".to_string(),
             _ => "An unexpected error has occurred".to_string(),
         }
     }
 }
 
+impl AppError {
+    /// Replaces the message when the error is of the given type,
+    /// so repositories can explain database errors in their own terms
+    pub fn with_message_for(self, error_type: AppErrorType, message: String) -> AppError {
+        match self.error_type == error_type {
+            true => AppError { message: Some(message), ..self },
+            false => self,
+        }
+    }
+
+    /// Names the input field behind the error when it is of the given type, like `with_message_for`
+    pub fn with_field_for(self, error_type: AppErrorType, field: &str) -> AppError {
+        match self.error_type == error_type {
+            true => AppError { field: Some(field.to_string()), ..self },
+            false => self,
+        }
+    }
+
+    /// The input field the error is about, the one it was given or the one all of its violations point at
+    pub fn field(&self) -> Option<&str> {
+        if let Some(field) = &self.field {
+            return Some(field.as_str());
+        }
+
+        match &self.error_type {
+            AppErrorType::ValidationError(violations) => {
+                let field = violations.first().map(|violation| violation.field.as_str());
+                match violations.iter().all(|violation| Some(violation.field.as_str()) == field) {
+                    true => field,
+                    false => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Clients get a code and an id they can report, the cause only goes to the log under that id
 impl IntoFieldError for AppError {
-fn into_field_error(self) -> juniper::FieldError { 
-    FieldError::new(self.message(), Value::null())
- }
+    fn into_field_error(self) -> juniper::FieldError {
+        let id = Uuid::new_v4().to_string();
+        let code = self.error_type.code();
+        let cause = self.cause.clone().unwrap_or_default();
+
+        match self.error_type {
+            AppErrorType::DbError | AppErrorType::HashingError => {
+                error!("{}", self.message(); "error_id" => &id, "code" => code, "cause" => cause)
+            }
+            _ => info!("{}", self.message(); "error_id" => &id, "code" => code, "cause" => cause),
+        }
+
+        let mut extensions = Object::with_capacity(4);
+        extensions.add_field("code", Value::scalar(code.to_string()));
+        extensions.add_field("id", Value::scalar(id));
+
+        if let Some(field) = self.field() {
+            extensions.add_field("field", Value::scalar(field.to_string()));
+        }
+
+        if let AppErrorType::ValidationError(violations) = &self.error_type {
+            let violations = violations
+                .iter()
+                .map(|violation| {
+                    let mut object = Object::with_capacity(3);
+                    object.add_field("field", Value::scalar(violation.field.clone()));
+                    object.add_field("code", Value::scalar(violation.code.clone()));
+                    object.add_field("message", Value::scalar(violation.message.clone()));
+                    Value::object(object)
+                })
+                .collect();
+
+            extensions.add_field("violations", Value::list(violations));
+        }
+
+        FieldError::new(self.message(), Value::object(extensions))
+    }
 }
 
 impl From<PoolError> for AppError {
     fn from(error: PoolError) -> AppError {
-        AppError {
-            message: None,
-            cause: Some(error.to_string()),
-            error_type: AppErrorType::DbError,
+        match error {
+            PoolError::Timeout(_) => AppError {
+                message: None,
+                cause: Some(error.to_string()),
+                error_type: AppErrorType::Timeout,
+                field: None,
+            },
+            PoolError::Backend(error) => AppError::from(error),
         }
     }
 }
 
+/// What a database error means for the client, by its SQLSTATE
+fn sql_error_type(code: &SqlState) -> AppErrorType {
+    match code {
+        c if c == &SqlState::UNIQUE_VIOLATION || c == &SqlState::EXCLUSION_VIOLATION => AppErrorType::Conflict,
+        c if c == &SqlState::FOREIGN_KEY_VIOLATION
+            || c == &SqlState::CHECK_VIOLATION
+            || c == &SqlState::NOT_NULL_VIOLATION
+            || c == &SqlState::STRING_DATA_RIGHT_TRUNCATION
+            || c == &SqlState::INVALID_TEXT_REPRESENTATION
+            || c == &SqlState::NUMERIC_VALUE_OUT_OF_RANGE => AppErrorType::InvalidField,
+        c if c == &SqlState::QUERY_CANCELED || c == &SqlState::LOCK_NOT_AVAILABLE => AppErrorType::Timeout,
+        _ => AppErrorType::DbError,
+    }
+}
+
+/// Name of the constraint a database error violated, if any
+pub fn violated_constraint(error: &Error) -> Option<&str> {
+    error
+        .source()
+        .and_then(|source| source.downcast_ref::<DbError>())
+        .and_then(|db_error| db_error.constraint())
+}
+
 impl From<Error> for AppError {
     fn from(error: Error) -> AppError {
         AppError {
             message: None,
             cause: Some(error.to_string()),
-            error_type: AppErrorType::DbError,
+            error_type: error.code().map_or(AppErrorType::DbError, sql_error_type),
+            field: None,
         }
     }
 }
@@ -73,6 +240,7 @@ impl From<tokio_pg_mapper::Error> for AppError {
             message: None,
             cause: Some(error.to_string()),
             error_type: AppErrorType::DbError,
+            field: None,
         }
     }
 }
@@ -92,8 +260,15 @@ impl ResponseError for AppError {
     fn status_code(&self) -> StatusCode {
         match self.error_type {
             AppErrorType::DbError => StatusCode::INTERNAL_SERVER_ERROR,
+            AppErrorType::HashingError => StatusCode::INTERNAL_SERVER_ERROR,
             AppErrorType::NotFoundError => StatusCode::NOT_FOUND,
             AppErrorType::InvalidField => StatusCode::BAD_REQUEST,
+            AppErrorType::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppErrorType::Forbidden => StatusCode::FORBIDDEN,
+            AppErrorType::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            AppErrorType::Conflict => StatusCode::CONFLICT,
+            AppErrorType::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            AppErrorType::ValidationError(_) => StatusCode::BAD_REQUEST,
         }
     }
     fn error_response(&self) -> HttpResponse {
@@ -106,8 +281,11 @@ impl ResponseError for AppError {
 #[cfg(test)]
 mod tests {
 
-    use super::{AppError, AppErrorType};
+    use super::{sql_error_type, AppError, AppErrorType};
+    use crate::validation::Violation;
+    use tokio_postgres::error::SqlState;
     use actix_web::error::ResponseError;
+    use juniper::IntoFieldError;
 
     #[test]
     fn test_default_db_error() {
@@ -115,6 +293,7 @@ mod tests {
             message: None,
             cause: None,
             error_type: AppErrorType::DbError,
+            field: None,
         };
 
         assert_eq!(
@@ -130,6 +309,7 @@ mod tests {
             message: None,
             cause: None,
             error_type: AppErrorType::NotFoundError,
+            field: None,
         };
 
         assert_eq!(
@@ -147,6 +327,7 @@ mod tests {
             message: Some(user_message.clone()),
             cause: None,
             error_type: AppErrorType::DbError,
+            field: None,
         };
 
         assert_eq!(
@@ -164,6 +345,7 @@ mod tests {
             message: None,
             cause: None,
             error_type: AppErrorType::DbError,
+            field: None,
         };
 
         assert_eq!(
@@ -173,4 +355,229 @@ mod tests {
             expected
         );
     }
+
+    #[test]
+    fn test_hashing_error_status_code() {
+        let expected = 500;
+
+        let hashing_error = AppError {
+            message: None,
+            cause: None,
+            error_type: AppErrorType::HashingError,
+            field: None,
+        };
+
+        assert_eq!(
+            hashing_error.status_code(),
+            expected,
+            "Status code for HashingError should be {}",
+            expected
+        );
+    }
+
+    #[test]
+    fn test_unauthorized_status_code() {
+        let expected = 401;
+
+        let unauthorized = AppError {
+            message: None,
+            cause: None,
+            error_type: AppErrorType::Unauthorized,
+            field: None,
+        };
+
+        assert_eq!(
+            unauthorized.status_code(),
+            expected,
+            "Status code for Unauthorized should be {}",
+            expected
+        );
+    }
+
+    #[test]
+    fn test_forbidden_status_code() {
+        let expected = 403;
+
+        let forbidden = AppError {
+            message: None,
+            cause: None,
+            error_type: AppErrorType::Forbidden,
+            field: None,
+        };
+
+        assert_eq!(
+            forbidden.status_code(),
+            expected,
+            "Status code for Forbidden should be {}",
+            expected
+        );
+    }
+
+    #[test]
+    fn test_rate_limited_status_code() {
+        let expected = 429;
+
+        let rate_limited = AppError {
+            message: None,
+            cause: None,
+            error_type: AppErrorType::RateLimited,
+            field: None,
+        };
+
+        assert_eq!(
+            rate_limited.status_code(),
+            expected,
+            "Status code for RateLimited should be {}",
+            expected
+        );
+    }
+
+    #[test]
+    fn test_validation_error_status_code() {
+        let expected = 400;
+
+        let validation_error = AppError {
+            message: None,
+            cause: None,
+            error_type: AppErrorType::ValidationError(vec![]),
+            field: None,
+        };
+
+        assert_eq!(
+            validation_error.status_code(),
+            expected,
+            "Status code for ValidationError should be {}",
+            expected
+        );
+    }
+
+    #[test]
+    fn test_field_error_hides_cause() {
+        let db_error = AppError {
+            message: None,
+            cause: Some("password authentication failed for user postgres".to_string()),
+            error_type: AppErrorType::DbError,
+            field: None,
+        };
+
+        let field_error = db_error.into_field_error();
+        let extensions = field_error.extensions().as_object_value().unwrap();
+
+        assert_eq!(
+            field_error.message(),
+            "An unexpected error has occurred",
+            "Default message should be shown"
+        );
+        assert_eq!(
+            extensions.get_field_value("code").and_then(|code| code.as_string_value()),
+            Some("INTERNAL_ERROR"),
+            "Code should be sent"
+        );
+        assert!(extensions.get_field_value("id").is_some(), "Error id should be sent");
+        assert!(extensions.get_field_value("cause").is_none(), "Cause should not be sent");
+    }
+
+    #[test]
+    fn test_validation_error_field() {
+        let violation = |field: &str| Violation {
+            field: field.to_string(),
+            code: "invalid".to_string(),
+            message: "Invalid".to_string(),
+        };
+
+        let single = AppError {
+            message: None,
+            cause: None,
+            error_type: AppErrorType::ValidationError(vec![violation("slug"), violation("slug")]),
+            field: None,
+        };
+        let several = AppError {
+            message: None,
+            cause: None,
+            error_type: AppErrorType::ValidationError(vec![violation("slug"), violation("title")]),
+            field: None,
+        };
+
+        assert_eq!(single.field(), Some("slug"), "Field should be known when all violations share it");
+        assert_eq!(several.field(), None, "Field should be left out when violations differ");
+    }
+
+    #[test]
+    fn test_conflict_status_code() {
+        let expected = 409;
+
+        let conflict = AppError {
+            message: None,
+            cause: None,
+            error_type: AppErrorType::Conflict,
+            field: None,
+        };
+
+        assert_eq!(
+            conflict.status_code(),
+            expected,
+            "Status code for Conflict should be {}",
+            expected
+        );
+    }
+
+    #[test]
+    fn test_timeout_status_code() {
+        let expected = 504;
+
+        let timeout = AppError {
+            message: None,
+            cause: None,
+            error_type: AppErrorType::Timeout,
+            field: None,
+        };
+
+        assert_eq!(
+            timeout.status_code(),
+            expected,
+            "Status code for Timeout should be {}",
+            expected
+        );
+    }
+
+    #[test]
+    fn test_sql_error_type() {
+        assert_eq!(sql_error_type(&SqlState::UNIQUE_VIOLATION), AppErrorType::Conflict, "Unique violations should be conflicts");
+        assert_eq!(sql_error_type(&SqlState::FOREIGN_KEY_VIOLATION), AppErrorType::InvalidField, "Foreign key violations should be invalid fields");
+        assert_eq!(sql_error_type(&SqlState::QUERY_CANCELED), AppErrorType::Timeout, "Canceled queries should be timeouts");
+        assert_eq!(sql_error_type(&SqlState::T_R_DEADLOCK_DETECTED), AppErrorType::DbError, "Deadlocks are our problem, not a conflict");
+        assert_eq!(sql_error_type(&SqlState::UNDEFINED_TABLE), AppErrorType::DbError, "Anything else should be a DbError");
+    }
+
+    #[test]
+    fn test_with_message_for() {
+        let conflict = AppError {
+            message: None,
+            cause: None,
+            error_type: AppErrorType::Conflict,
+            field: None,
+        };
+
+        let described = conflict.clone().with_message_for(AppErrorType::Conflict, "Slug taken".to_string());
+        let untouched = conflict.with_message_for(AppErrorType::InvalidField, "Unknown author".to_string());
+
+        assert_eq!(described.message(), "Slug taken", "Matching errors should get the message");
+        assert_eq!(untouched.message(), "This conflicts with an existing item", "Other errors should keep theirs");
+    }
+
+    #[test]
+    fn test_with_field_for() {
+        let conflict = AppError {
+            message: None,
+            cause: None,
+            error_type: AppErrorType::Conflict,
+            field: None,
+        };
+
+        let named = conflict.clone().with_field_for(AppErrorType::Conflict, "slug");
+        let untouched = conflict.with_field_for(AppErrorType::InvalidField, "author");
+
+        assert_eq!(named.field(), Some("slug"), "Matching errors should get the field");
+        assert_eq!(untouched.field(), None, "Other errors should not");
+    }
 }
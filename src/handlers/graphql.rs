@@ -1,29 +1,328 @@
-use crate::{config::HashingService, errors::AppError, models::post::{CreatePost, Post}, repositories::post::{PostLoader, PostRepository}};
+use crate::{config::{AccountSettings, CommentSettings, HashingService, TokenService}, errors::{AppError, AppErrorType}, models::post::{CreatePost, Post, UpdatePost}, repositories::post::{PostByIdLoader, PostLoader, PostRepository}};
 use crate::{
-    models::user::{CreateUser, User},
-    repositories::user::UserRepository,
+    mailer::Notifier,
+    validation::{validate, Validator, Violation},
+    models::{
+        access_token::{AccessToken, CreateAccessToken, Scope, ACCESS_TOKEN_PREFIX},
+        comment::{Comment, CommentOrder, CreateComment, UpdateComment},
+        login_attempt::ThrottleKey,
+        session::Session,
+        user::{CreateUser, Role, UpdateUser, User},
+        user_token::TokenPurpose,
+    },
+    repositories::{
+        access_token::AccessTokenRepository,
+        comment::{CommentByIdLoader, CommentLoader, CommentRepository},
+        follow::{FollowLoader, FollowRepository, FollowerCountLoader},
+        login_attempt::LoginAttemptRepository,
+        session::SessionRepository,
+        user::{UserLoader, UserRepository},
+        user_token::UserTokenRepository,
+    },
 };
 use actix_web::Result;
+use slog_scope::{error, warn};
 use chrono::NaiveDateTime;
 use deadpool_postgres::Pool;
 use uuid::Uuid;
-use std::sync::Arc;
+use std::{future::Future, sync::{Arc, Mutex}};
 use juniper::RootNode;
 
+/// Details about the client that sent the request
+#[derive(Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Requirements a resolver or field can place on the viewer
+pub enum Guard {
+    /// Has at least the given role
+    Role(Role),
+    /// Is the given user, or has at least the given role
+    SelfOrRole(Uuid, Role),
+}
+
+impl Guard {
+    fn allows(&self, viewer: &User) -> bool {
+        match self {
+            Guard::Role(role) => viewer.role >= *role,
+            Guard::SelfOrRole(user_id, role) => viewer.id == *user_id || viewer.role >= *role,
+        }
+    }
+}
+
+/// Visibility policy of a private field: only the user it belongs to
+/// and users with at least the given role can read it, everyone else gets null
+#[derive(Clone, Copy)]
+pub struct Private(pub Role);
+
+/// Private `User` fields
+const USER_EMAIL: Private = Private(Role::Admin);
+
 #[derive(Clone)]
 pub struct Context {
     pub pool: Arc<Pool>,
     pub hashing: Arc<HashingService>,
-    pub post_loader: PostLoader
+    pub tokens: Arc<TokenService>,
+    pub notifier: Arc<Notifier>,
+    pub post_loader: PostLoader,
+    pub post_by_id_loader: PostByIdLoader,
+    pub comments_loader: CommentLoader,
+    pub replies_loader: CommentLoader,
+    pub comment_by_id_loader: CommentByIdLoader,
+    pub comment_settings: CommentSettings,
+    pub account_settings: AccountSettings,
+    pub user_loader: UserLoader,
+    pub followers_loader: FollowLoader,
+    pub following_loader: FollowLoader,
+    pub follower_count_loader: FollowerCountLoader,
+    pub viewer: Option<User>,
+    pub session: Option<Session>,
+    pub access_token: Option<AccessToken>,
+    /// Why the request's bearer token was rejected, the request runs anonymously
+    pub credentials_error: Option<AppError>,
+    /// The user who signed up or signed in during this request, before any token names them
+    pub signed_in: Arc<Mutex<Option<Uuid>>>,
+    pub client: ClientInfo,
 }
 
 impl Context {
+    /// The authenticated user, or an Unauthorized error for anonymous requests
+    /// carrying the reason a bearer token was rejected, if one was sent
+    pub fn viewer(&self) -> Result<&User, AppError> {
+        self.viewer.as_ref().ok_or_else(|| {
+            self.credentials_error.clone().unwrap_or(AppError {
+                message: None,
+                cause: None,
+                error_type: AppErrorType::Unauthorized,
+                field: None,
+            })
+        })
+    }
+
+    /// The authenticated user, if they signed in with a password rather than an access token.
+    /// Required to manage the account itself.
+    pub fn session_viewer(&self) -> Result<&User, AppError> {
+        let viewer = self.viewer()?;
+
+        match &self.session {
+            Some(_) => Ok(viewer),
+            None => Err(AppError {
+                message: Some("Sign in with your password to do this".to_string()),
+                cause: None,
+                error_type: AppErrorType::Forbidden,
+                field: None,
+            }),
+        }
+    }
+
+    /// The authenticated user, if their credentials allow the scope. Sessions allow every scope.
+    pub fn scoped_viewer(&self, scope: Scope) -> Result<&User, AppError> {
+        let viewer = self.viewer()?;
+
+        match &self.access_token {
+            Some(access_token) if !access_token.scopes.contains(&scope) => Err(AppError {
+                message: Some(format!("Access token is missing the {} scope", scope.as_str())),
+                cause: None,
+                error_type: AppErrorType::Forbidden,
+                field: None,
+            }),
+            _ => Ok(viewer),
+        }
+    }
+
+    /// The authenticated user if the guard lets them through.
+    /// Anonymous requests are Unauthorized, everyone else the guard rejects is Forbidden.
+    pub fn guard(&self, guard: Guard) -> Result<&User, AppError> {
+        let viewer = self.viewer()?;
+
+        match guard.allows(viewer) {
+            true => Ok(viewer),
+            false => Err(AppError {
+                message: None,
+                cause: None,
+                error_type: AppErrorType::Forbidden,
+                field: None,
+            }),
+        }
+    }
+
+    /// Whether the user signed up or signed in during this request
+    pub fn signed_in_as(&self, user_id: Uuid) -> bool {
+        *self.signed_in.lock().unwrap() == Some(user_id)
+    }
+
+    /// Lets the user read their own private fields in the response that signed them in
+    pub fn mark_signed_in(&self, user_id: Uuid) {
+        *self.signed_in.lock().unwrap() = Some(user_id);
+    }
+
+    /// The value of a private field if the viewer is allowed to read it
+    pub fn reveal<T>(&self, owner_id: Uuid, field: Private, value: T) -> Option<T> {
+        let allowed = self.signed_in_as(owner_id)
+            || self
+                .viewer
+                .as_ref()
+                .map_or(false, |viewer| Guard::SelfOrRole(owner_id, field.0).allows(viewer));
+
+        match allowed {
+            true => Some(value),
+            false => None,
+        }
+    }
+
     pub fn user_repository(&self) -> UserRepository {
         UserRepository::new(self.pool.clone())
     }
     pub fn post_repository(&self) -> PostRepository {
         PostRepository::new(self.pool.clone())
     }
+    pub fn session_repository(&self) -> SessionRepository {
+        SessionRepository::new(self.pool.clone())
+    }
+    pub fn user_token_repository(&self) -> UserTokenRepository {
+        UserTokenRepository::new(self.pool.clone())
+    }
+    pub fn follow_repository(&self) -> FollowRepository {
+        FollowRepository::new(self.pool.clone())
+    }
+    pub fn access_token_repository(&self) -> AccessTokenRepository {
+        AccessTokenRepository::new(self.pool.clone())
+    }
+    pub fn login_attempt_repository(&self) -> LoginAttemptRepository {
+        LoginAttemptRepository::new(self.pool.clone())
+    }
+    pub fn comment_repository(&self) -> CommentRepository {
+        CommentRepository::new(self.pool.clone())
+    }
+
+    /// Runs a password check unless the account or the client's IP is locked out.
+    /// Every check counts against both until it turns out not to be a wrong password,
+    /// a success clears the account's failures.
+    pub async fn throttled<F>(&self, account: ThrottleKey, attempt: F) -> Result<User, AppError>
+    where
+        F: Future<Output = Result<User, AppError>>,
+    {
+        let mut keys = vec![account.clone()];
+        if let Some(ip) = &self.client.ip {
+            keys.push(ThrottleKey::Ip(ip.clone()));
+        }
+
+        let attempts = self.login_attempt_repository();
+        let recorded = attempts.record_attempt(&keys).await?;
+
+        match attempt.await {
+            Ok(user) => {
+                attempts.clear(&[account]).await?;
+                attempts.release(&keys[1..]).await?;
+                Ok(user)
+            }
+            Err(err) => {
+                match err.error_type {
+                    AppErrorType::Unauthorized => {
+                        for attempt in recorded {
+                            if let Some(locked_until) = attempt.locked_until {
+                                warn!("Login locked out";
+                                    "event" => "login_lockout",
+                                    "key" => attempt.key,
+                                    "failures" => attempt.failures,
+                                    "locked_until" => locked_until.to_string(),
+                                    "user_agent" => self.client.user_agent.clone().unwrap_or_default());
+                            }
+                        }
+                    }
+                    _ => attempts.release(&keys).await?,
+                }
+
+                Err(err)
+            }
+        }
+    }
+
+    /// What failed logins for the username or email count against
+    pub fn account_key(user: Option<&User>, username_or_email: &str) -> ThrottleKey {
+        match user {
+            Some(user) => ThrottleKey::Account(user.id),
+            None => ThrottleKey::login(username_or_email),
+        }
+    }
+
+    /// Opens a new session for the user and issues its tokens
+    pub async fn sign_in(&self, user: User) -> Result<AuthPayload, AppError> {
+        let refresh_token = TokenService::opaque_token();
+
+        let session = self
+            .session_repository()
+            .create(
+                user.id,
+                &TokenService::digest(&refresh_token),
+                self.client.user_agent.clone(),
+                self.client.ip.clone(),
+                self.tokens.refresh_token_ttl(),
+            )
+            .await?;
+
+        let access_token = self.tokens.access_token(user.id, session.id)?;
+        self.mark_signed_in(user.id);
+
+        Ok(AuthPayload { access_token, refresh_token, user })
+    }
+
+    /// Soft-deletes the account and signs it out everywhere
+    pub async fn delete_account(&self, user_id: Uuid, deleted_by: Uuid) -> Result<bool, AppError> {
+        if !self.user_repository().soft_delete(user_id, deleted_by).await? {
+            return Err(AppError {
+                cause: None,
+                message: Some(format!("User with id {} not found", user_id)),
+                error_type: AppErrorType::NotFoundError,
+                field: None,
+            });
+        }
+
+        self.session_repository().revoke_all(user_id).await?;
+
+        Ok(true)
+    }
+
+    /// Issues a new email verification token for the user's current address and mails it there
+    pub async fn send_email_verification(&self, user: &User) -> Result<(), AppError> {
+        let token = TokenService::opaque_token();
+
+        self.user_token_repository()
+            .issue(
+                user.id,
+                TokenPurpose::EmailVerification,
+                &TokenService::digest(&token),
+                Some(&user.email),
+                self.tokens.email_verification_ttl(),
+            )
+            .await?;
+
+        self.notifier.email_verification(&user.email, &token).await
+    }
+
+    /// Issues a password reset token and mails it, if the email belongs to an account
+    pub async fn send_password_reset(&self, email: &str) -> Result<(), AppError> {
+        let user = match self.user_repository().find_by_email(email).await? {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+
+        let token = TokenService::opaque_token();
+
+        self.user_token_repository()
+            .issue(
+                user.id,
+                TokenPurpose::PasswordReset,
+                &TokenService::digest(&token),
+                None,
+                self.tokens.password_reset_ttl(),
+            )
+            .await?;
+
+        self.notifier.password_reset(&user.email, &token).await
+    }
 }
 
 impl juniper::Context for Context {}
@@ -38,6 +337,22 @@ impl Query {
         "1.0"
     }
 
+    pub fn me(context: &Context) -> Option<User> {
+        context.viewer.clone()
+    }
+
+    pub async fn my_sessions(context: &Context) -> Result<Vec<Session>, AppError> {
+        let viewer = context.session_viewer()?;
+
+        context.session_repository().for_user(viewer.id).await
+    }
+
+    pub async fn my_access_tokens(context: &Context) -> Result<Vec<AccessToken>, AppError> {
+        let viewer = context.session_viewer()?;
+
+        context.access_token_repository().for_user(viewer.id).await
+    }
+
     pub async fn users(context: &Context) -> Result<Vec<User>, AppError> {
         context.user_repository().all().await
     }
@@ -53,6 +368,12 @@ impl Query {
     pub async fn post(id: Uuid, context: &Context) -> Result<Post, AppError> {
         context.post_repository().get(id).await
     }
+
+    /// Also finds posts by slugs they had before. When the returned `slug` differs
+    /// from the requested one it is the canonical slug to redirect to.
+    pub async fn post_by_slug(slug: String, context: &Context) -> Result<Post, AppError> {
+        context.post_repository().get_by_slug(&slug).await
+    }
 }
 
 #[juniper::graphql_object(
@@ -67,8 +388,20 @@ impl User {
         self.username.as_str()
     }
 
-    pub fn email(&self) -> &str {
-        self.email.as_str()
+    pub fn email(&self, context: &Context) -> Option<&str> {
+        context.reveal(self.id, USER_EMAIL, self.email.as_str())
+    }
+
+    pub fn email_verified(&self) -> bool {
+        self.email_verified
+    }
+
+    pub fn role(&self, context: &Context) -> Result<Role, AppError> {
+        if !context.signed_in_as(self.id) {
+            context.guard(Guard::SelfOrRole(self.id, Role::Moderator))?;
+        }
+
+        Ok(self.role)
     }
 
     pub fn bio(&self) -> Option<&str> {
@@ -91,6 +424,237 @@ impl User {
         // context.post_repository().get_for_user(self.id).await
         context.post_loader.load(self.id).await
     }
+
+    pub async fn followers(&self, context: &Context) -> Result<Vec<User>, AppError> {
+        context.followers_loader.load(self.id).await
+    }
+
+    pub async fn following(&self, context: &Context) -> Result<Vec<User>, AppError> {
+        context.following_loader.load(self.id).await
+    }
+
+    pub async fn follower_count(&self, context: &Context) -> Result<i32, AppError> {
+        let count = context.follower_count_loader.load(self.id).await?;
+
+        Ok(count as i32)
+    }
+
+    pub async fn viewer_is_following(&self, context: &Context) -> Result<bool, AppError> {
+        let viewer = match &context.viewer {
+            Some(viewer) => viewer,
+            None => return Ok(false),
+        };
+
+        let following = context.following_loader.load(viewer.id).await?;
+
+        Ok(following.iter().any(|user| user.id == self.id))
+    }
+}
+
+#[juniper::graphql_object(
+    Context = Context,
+)]
+impl Post {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn author_id(&self) -> Uuid {
+        self.author_id
+    }
+
+    pub async fn author(&self, context: &Context) -> Result<User, AppError> {
+        context.user_loader.load(self.author_id).await
+    }
+
+    pub fn slug(&self) -> &str {
+        self.slug.as_str()
+    }
+
+    pub fn title(&self) -> &str {
+        self.title.as_str()
+    }
+
+    pub fn description(&self) -> &str {
+        self.description.as_str()
+    }
+
+    pub fn body(&self) -> &str {
+        self.body.as_str()
+    }
+
+    pub fn created_at(&self) -> NaiveDateTime {
+        self.created_at
+    }
+
+    pub fn updated_at(&self) -> NaiveDateTime {
+        self.updated_at
+    }
+
+    /// Top level comments, oldest first unless ordered otherwise
+    pub async fn comments(&self, order: Option<CommentOrder>, context: &Context) -> Result<Vec<Comment>, AppError> {
+        let mut comments = context.comments_loader.load(self.id).await?;
+        order.unwrap_or(CommentOrder::Oldest).sort(&mut comments);
+
+        Ok(comments)
+    }
+}
+
+#[juniper::graphql_object(
+    Context = Context,
+)]
+impl Comment {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub async fn author(&self, context: &Context) -> Result<User, AppError> {
+        context.user_loader.load(self.author_id).await
+    }
+
+    pub async fn post(&self, context: &Context) -> Result<Post, AppError> {
+        context.post_by_id_loader.load(self.post_id).await
+    }
+
+    pub async fn parent(&self, context: &Context) -> Result<Option<Comment>, AppError> {
+        match self.parent_id {
+            Some(parent_id) => context.comment_by_id_loader.load(parent_id).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Direct replies, oldest first unless ordered otherwise
+    pub async fn replies(&self, order: Option<CommentOrder>, context: &Context) -> Result<Vec<Comment>, AppError> {
+        let mut replies = context.replies_loader.load(self.id).await?;
+        order.unwrap_or(CommentOrder::Oldest).sort(&mut replies);
+
+        Ok(replies)
+    }
+
+    pub fn reply_count(&self) -> i32 {
+        self.reply_count as i32
+    }
+
+    pub fn depth(&self) -> i32 {
+        self.depth
+    }
+
+    pub fn body(&self) -> &str {
+        self.body.as_str()
+    }
+
+    pub fn created_at(&self) -> NaiveDateTime {
+        self.created_at
+    }
+
+    pub fn updated_at(&self) -> NaiveDateTime {
+        self.updated_at
+    }
+}
+
+#[juniper::graphql_object(
+    Context = Context,
+)]
+impl Session {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+
+    pub fn ip(&self) -> Option<&str> {
+        self.ip.as_deref()
+    }
+
+    pub fn created_at(&self) -> NaiveDateTime {
+        self.created_at
+    }
+
+    pub fn last_seen_at(&self) -> NaiveDateTime {
+        self.last_seen_at
+    }
+
+    pub fn expires_at(&self) -> NaiveDateTime {
+        self.expires_at
+    }
+
+    /// Whether this is the session making the request
+    pub fn current(&self, context: &Context) -> bool {
+        context.session.as_ref().map(|session| session.id) == Some(self.id)
+    }
+}
+
+#[juniper::graphql_object(
+    Context = Context,
+)]
+impl AccessToken {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn scopes(&self) -> Vec<Scope> {
+        self.scopes.clone()
+    }
+
+    pub fn created_at(&self) -> NaiveDateTime {
+        self.created_at
+    }
+
+    pub fn last_used_at(&self) -> Option<NaiveDateTime> {
+        self.last_used_at
+    }
+
+    pub fn expires_at(&self) -> Option<NaiveDateTime> {
+        self.expires_at
+    }
+}
+
+/// A new personal access token, its value is only shown once
+pub struct CreatedAccessToken {
+    pub token: String,
+    pub access_token: AccessToken,
+}
+
+#[juniper::graphql_object(
+    Context = Context,
+)]
+impl CreatedAccessToken {
+    pub fn token(&self) -> &str {
+        self.token.as_str()
+    }
+
+    pub fn access_token(&self) -> AccessToken {
+        self.access_token.clone()
+    }
+}
+
+pub struct AuthPayload {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub user: User,
+}
+
+#[juniper::graphql_object(
+    Context = Context,
+)]
+impl AuthPayload {
+    pub fn access_token(&self) -> &str {
+        self.access_token.as_str()
+    }
+
+    pub fn refresh_token(&self) -> &str {
+        self.refresh_token.as_str()
+    }
+
+    pub fn user(&self) -> User {
+        self.user.clone()
+    }
 }
 
 pub struct Mutation {}
@@ -100,17 +664,412 @@ pub struct Mutation {}
 )]
 impl Mutation {
     pub async fn create_user(input: CreateUser, context: &Context) -> Result<User, AppError> {
-        context
+        validate(&input)?;
+
+        let user = context
             .user_repository()
             .create(input, context.hashing.clone())
+            .await?;
+
+        // The account exists either way, the user can ask for the email again
+        if let Err(err) = context.send_email_verification(&user).await {
+            error!("Error sending verification email. {}", err; "cause" => err.cause.clone().unwrap_or_default());
+        }
+
+        context.mark_signed_in(user.id);
+
+        Ok(user)
+    }
+    /// Access tokens with the write_profile scope can change the bio and image,
+    /// the username and email lead to account recovery so they need a password session
+    pub async fn update_user(input: UpdateUser, context: &Context) -> Result<User, AppError> {
+        let viewer = match input.username.is_some() || input.email.is_some() {
+            true => context.session_viewer()?,
+            false => context.scoped_viewer(Scope::WriteProfile)?,
+        };
+        validate(&input)?;
+
+        let user = context
+            .user_repository()
+            .update(viewer.id, input)
+            .await?;
+
+        // Links sent to the old address must not verify the new one
+        if user.email.to_lowercase() != viewer.email.to_lowercase() {
+            context
+                .user_token_repository()
+                .revoke(user.id, TokenPurpose::EmailVerification)
+                .await?;
+
+            if let Err(err) = context.send_email_verification(&user).await {
+                error!("Error sending verification email. {}", err; "cause" => err.cause.clone().unwrap_or_default());
+            }
+        }
+
+        Ok(user)
+    }
+    pub async fn change_password(current_password: String, new_password: String, context: &Context) -> Result<User, AppError> {
+        let viewer = context.session_viewer()?;
+        Validator::new().password("newPassword", &new_password).finish()?;
+
+        context
+            .throttled(
+                ThrottleKey::Account(viewer.id),
+                context.user_repository().check_password(viewer.clone(), current_password, context.hashing.clone()),
+            )
             .await
+            .map_err(|err| match err.error_type {
+                AppErrorType::Unauthorized => AppError {
+                    message: Some("Current password is incorrect".to_string()),
+                    cause: None,
+                    error_type: AppErrorType::InvalidField,
+                    field: None,
+                },
+                _ => err,
+            })?;
+
+        let user = context
+            .user_repository()
+            .update_password(viewer.id, new_password, context.hashing.clone())
+            .await?;
+
+        // Keep the device that changed the password signed in
+        match &context.session {
+            Some(session) => context.session_repository().revoke_others(user.id, session.id).await?,
+            None => context.session_repository().revoke_all(user.id).await?,
+        };
+
+        Ok(user)
+    }
+    pub async fn verify_email(token: String, context: &Context) -> Result<User, AppError> {
+        let verification = context
+            .user_token_repository()
+            .consume(&TokenService::digest(&token), TokenPurpose::EmailVerification)
+            .await?;
+
+        context
+            .user_repository()
+            .mark_email_verified(verification.user_id, &verification.email.unwrap_or_default())
+            .await
+    }
+    pub async fn resend_verification(context: &Context) -> Result<bool, AppError> {
+        let viewer = context.viewer()?;
+
+        if viewer.email_verified {
+            return Err(AppError {
+                message: Some("Email address already verified".to_string()),
+                cause: None,
+                error_type: AppErrorType::InvalidField,
+                field: None,
+            });
+        }
+
+        context.send_email_verification(viewer).await?;
+
+        Ok(true)
+    }
+    pub async fn login(username_or_email: String, password: String, context: &Context) -> Result<AuthPayload, AppError> {
+        let users = context.user_repository();
+        let user = users.find_by_login(&username_or_email).await?;
+        let user = context
+            .throttled(
+                Context::account_key(user.as_ref(), &username_or_email),
+                users.authenticate(user, password, context.hashing.clone()),
+            )
+            .await?;
+
+        context.sign_in(user).await
+    }
+    pub async fn refresh_token(refresh_token: String, context: &Context) -> Result<AuthPayload, AppError> {
+        let new_refresh_token = TokenService::opaque_token();
+
+        let session = context
+            .session_repository()
+            .rotate(
+                &TokenService::digest(&refresh_token),
+                &TokenService::digest(&new_refresh_token),
+                context.client.user_agent.clone(),
+                context.client.ip.clone(),
+                context.tokens.refresh_token_ttl(),
+            )
+            .await?;
+
+        let user = context.user_repository().get(session.user_id).await?;
+        let access_token = context.tokens.access_token(user.id, session.id)?;
+        context.mark_signed_in(user.id);
+
+        Ok(AuthPayload { access_token, refresh_token: new_refresh_token, user })
+    }
+    pub async fn logout(context: &Context) -> Result<bool, AppError> {
+        let viewer = context.viewer()?;
+
+        match &context.session {
+            Some(session) => context.session_repository().revoke(session.id, viewer.id).await,
+            None => Ok(false),
+        }
+    }
+    pub async fn logout_all_devices(context: &Context) -> Result<bool, AppError> {
+        let viewer = context.session_viewer()?;
+
+        let revoked = context.session_repository().revoke_all(viewer.id).await?;
+
+        Ok(revoked > 0)
+    }
+    pub async fn revoke_session(id: Uuid, context: &Context) -> Result<bool, AppError> {
+        let viewer = context.session_viewer()?;
+
+        context.session_repository().revoke(id, viewer.id).await
+    }
+    pub async fn request_password_reset(email: String, context: &Context) -> Result<bool, AppError> {
+        // Looked up and mailed in the background, so neither the result nor the response time
+        // tell whether the email belongs to an account
+        let context = context.clone();
+        actix_rt::spawn(async move {
+            if let Err(err) = context.send_password_reset(&email).await {
+                error!("Error sending password reset email. {}", err; "cause" => err.cause.clone().unwrap_or_default());
+            }
+        });
+
+        Ok(true)
+    }
+    pub async fn reset_password(token: String, new_password: String, context: &Context) -> Result<bool, AppError> {
+        Validator::new().password("newPassword", &new_password).finish()?;
+
+        // Hash before redeeming the token, a failure here leaves it usable
+        let password_hash = context.hashing.hash(new_password).await?;
+
+        let user = context
+            .user_repository()
+            .reset_password(&TokenService::digest(&token), password_hash)
+            .await?;
+
+        // Whoever knew the old password shouldn't stay signed in
+        context.session_repository().revoke_all(user.id).await?;
+        context.access_token_repository().revoke_all(user.id).await?;
+
+        Ok(true)
+    }
+    pub async fn delete_my_account(password: String, context: &Context) -> Result<bool, AppError> {
+        let viewer = context.session_viewer()?;
+
+        context
+            .throttled(
+                ThrottleKey::Account(viewer.id),
+                context.user_repository().check_password(viewer.clone(), password, context.hashing.clone()),
+            )
+            .await?;
+
+        context.delete_account(viewer.id, viewer.id).await
+    }
+    pub async fn delete_user(id: Uuid, context: &Context) -> Result<bool, AppError> {
+        let viewer = context.session_viewer()?;
+        context.guard(Guard::Role(Role::Admin))?;
+
+        context.delete_account(id, viewer.id).await
+    }
+    /// Brings back an account before its grace period is over, including ones an admin deleted
+    pub async fn restore_user(id: Uuid, context: &Context) -> Result<User, AppError> {
+        context.session_viewer()?;
+        context.guard(Guard::Role(Role::Admin))?;
+
+        context
+            .user_repository()
+            .restore(id, context.account_settings.deletion_grace_period)
+            .await
+    }
+    pub async fn restore_account(username_or_email: String, password: String, context: &Context) -> Result<AuthPayload, AppError> {
+        let users = context.user_repository();
+        let user = users
+            .find_deleted_by_login(&username_or_email, context.account_settings.deletion_grace_period)
+            .await?;
+        let user = context
+            .throttled(
+                Context::account_key(user.as_ref(), &username_or_email),
+                users.restore_account(
+                    user,
+                    password,
+                    context.account_settings.deletion_grace_period,
+                    context.hashing.clone(),
+                ),
+            )
+            .await?;
+
+        context.sign_in(user).await
+    }
+    /// Lifts the lockout on an account, and on an IP if given
+    pub async fn clear_login_lockout(username_or_email: String, ip: Option<String>, context: &Context) -> Result<bool, AppError> {
+        context.session_viewer()?;
+        context.guard(Guard::Role(Role::Admin))?;
+
+        let user = context.user_repository().find_by_login(&username_or_email).await?;
+
+        let mut keys = vec![Context::account_key(user.as_ref(), &username_or_email)];
+        if let Some(ip) = ip {
+            keys.push(ThrottleKey::Ip(ip));
+        }
+
+        context.login_attempt_repository().clear(&keys).await
+    }
+    pub async fn create_access_token(input: CreateAccessToken, context: &Context) -> Result<CreatedAccessToken, AppError> {
+        let viewer = context.session_viewer()?;
+        validate(&input)?;
+
+        let token = format!("{}{}", ACCESS_TOKEN_PREFIX, TokenService::opaque_token());
+        let ttl = input.expires_in_days.map(|days| i64::from(days) * 24 * 60 * 60);
+
+        let access_token = context
+            .access_token_repository()
+            .create(viewer.id, &input.name, &input.scopes, &TokenService::digest(&token), ttl)
+            .await?;
+
+        Ok(CreatedAccessToken { token, access_token })
+    }
+    pub async fn revoke_access_token(id: Uuid, context: &Context) -> Result<bool, AppError> {
+        let viewer = context.session_viewer()?;
+
+        context.access_token_repository().revoke(id, viewer.id).await
+    }
+    pub async fn follow_user(user_id: Uuid, context: &Context) -> Result<bool, AppError> {
+        let viewer = context.scoped_viewer(Scope::WriteProfile)?;
+
+        // Fails for unknown and deleted users
+        context.user_repository().get(user_id).await?;
+
+        context.follow_repository().follow(viewer.id, user_id).await
+    }
+    pub async fn unfollow_user(user_id: Uuid, context: &Context) -> Result<bool, AppError> {
+        let viewer = context.scoped_viewer(Scope::WriteProfile)?;
+
+        context.follow_repository().unfollow(viewer.id, user_id).await
+    }
+    pub async fn set_user_role(user_id: Uuid, role: Role, context: &Context) -> Result<User, AppError> {
+        context.session_viewer()?;
+        context.guard(Guard::Role(Role::Admin))?;
+
+        context.user_repository().set_role(user_id, role).await
     }
     pub async fn create_post(input: CreatePost, context: &Context) -> Result<Post, AppError> {
+        context.scoped_viewer(Scope::WritePosts)?;
+        let viewer = context.guard(Guard::Role(Role::Author))?;
+
+        if !viewer.email_verified {
+            return Err(AppError {
+                message: Some("Verify your email address before posting".to_string()),
+                cause: None,
+                error_type: AppErrorType::Forbidden,
+                field: None,
+            });
+        }
+
+        validate(&input)?;
+
         context
             .post_repository()
-            .create(input)
+            .create(viewer.id, input)
             .await
     }
+    /// Only the author or a moderator can edit a post
+    pub async fn update_post(id: Uuid, input: UpdatePost, context: &Context) -> Result<Post, AppError> {
+        context.scoped_viewer(Scope::WritePosts)?;
+        let post = context.post_repository().get(id).await?;
+
+        context.guard(Guard::SelfOrRole(post.author_id, Role::Moderator))?;
+        validate(&input)?;
+
+        let post = context.post_repository().update(id, input).await?;
+
+        context.post_loader.clear(post.author_id).await;
+        context.post_by_id_loader.clear(post.id).await;
+
+        Ok(post)
+    }
+    /// Only the author or a moderator can delete a post, its comments go with it
+    pub async fn delete_post(id: Uuid, context: &Context) -> Result<bool, AppError> {
+        context.scoped_viewer(Scope::WritePosts)?;
+        let post = context.post_repository().get(id).await?;
+
+        context.guard(Guard::SelfOrRole(post.author_id, Role::Moderator))?;
+
+        let deleted = context.post_repository().delete(id).await?;
+
+        context.post_loader.clear(post.author_id).await;
+        context.post_by_id_loader.clear(post.id).await;
+        context.comments_loader.clear(post.id).await;
+
+        Ok(deleted)
+    }
+    pub async fn create_comment(input: CreateComment, context: &Context) -> Result<Comment, AppError> {
+        let viewer = context.scoped_viewer(Scope::WriteComments)?;
+
+        if !viewer.email_verified {
+            return Err(AppError {
+                message: Some("Verify your email address before commenting".to_string()),
+                cause: None,
+                error_type: AppErrorType::Forbidden,
+                field: None,
+            });
+        }
+
+        validate(&input)?;
+
+        let parent = match input.parent_id {
+            Some(parent_id) => Some(context.comment_repository().get(parent_id).await?),
+            None => None,
+        };
+
+        if let Some(parent) = &parent {
+            if parent.post_id != input.post_id {
+                return Err(AppError {
+                    message: Some("Replies must be on the same post as their parent".to_string()),
+                    cause: None,
+                    error_type: AppErrorType::InvalidField,
+                    field: None,
+                });
+            }
+
+            if parent.depth >= context.comment_settings.max_depth {
+                return Err(AppError {
+                    message: None,
+                    cause: None,
+                    error_type: AppErrorType::ValidationError(vec![Violation {,
+                    field: None
+                        field: "parentId".to_string(),
+                        code: "too_deep".to_string(),
+                        message: format!("Replies can't be nested more than {} levels deep", context.comment_settings.max_depth),
+                    }]),
+                });
+            }
+        }
+
+        context.comment_repository().create(viewer.id, input, parent.as_ref()).await
+    }
+    /// Only the author can edit a comment
+    pub async fn update_comment(id: Uuid, input: UpdateComment, context: &Context) -> Result<Comment, AppError> {
+        let viewer = context.scoped_viewer(Scope::WriteComments)?;
+        let comment = context.comment_repository().get(id).await?;
+
+        if comment.author_id != viewer.id {
+            return Err(AppError {
+                message: Some("You can only edit your own comments".to_string()),
+                cause: None,
+                error_type: AppErrorType::Forbidden,
+                field: None,
+            });
+        }
+
+        validate(&input)?;
+
+        context.comment_repository().update(id, input).await
+    }
+    /// Authors can delete their comments, moderators anyone's. Replies are kept and move up to the top level.
+    pub async fn delete_comment(id: Uuid, context: &Context) -> Result<bool, AppError> {
+        context.scoped_viewer(Scope::WriteComments)?;
+        let comment = context.comment_repository().get(id).await?;
+
+        context.guard(Guard::SelfOrRole(comment.author_id, Role::Moderator))?;
+
+        context.comment_repository().delete(id).await
+    }
 }
 
 pub type Schema = RootNode<'static, Query, Mutation>;
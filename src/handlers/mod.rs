@@ -1,11 +1,24 @@
 mod graphql;
 
-use actix_web::{web, HttpResponse};
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
 use deadpool_postgres::Pool;
-use graphql::{create_schema, Context, Schema};
+use graphql::{create_schema, ClientInfo, Context, Schema};
 use juniper::http::{graphiql::graphiql_source, GraphQLRequest};
-use std::sync::Arc;
-use crate::{config::HashingService, repositories::post::get_posts_loader};
+use std::sync::{Arc, Mutex};
+use crate::{
+    config::{AccountSettings, CommentSettings, HashingService, TokenService, TrustedProxies},
+    mailer::Notifier,
+    errors::{AppError, AppErrorType},
+    models::{access_token::{AccessToken, ACCESS_TOKEN_PREFIX}, session::Session, user::User},
+    repositories::{
+        access_token::AccessTokenRepository,
+        comment::{get_comment_by_id_loader, get_comments_loader, get_replies_loader},
+        follow::{get_follower_count_loader, get_followers_loader, get_following_loader},
+        post::{get_post_by_id_loader, get_posts_loader},
+        session::SessionRepository,
+        user::{get_users_loader, UserRepository},
+    },
+};
 
 async fn health() -> HttpResponse {
     HttpResponse::Ok().finish()
@@ -28,18 +41,150 @@ async fn graphiql() -> HttpResponse {
         .body(html)
 }
 
+/// Extracts the token from an `Authorization: Bearer <token>` header
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            if value.starts_with("Bearer ") {
+                Some(value["Bearer ".len()..].trim())
+            } else {
+                None
+            }
+        })
+}
+
+fn client_info(req: &HttpRequest, trusted_proxies: &TrustedProxies) -> ClientInfo {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    let forwarded_for = req
+        .headers()
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok());
+
+    // Drop the port so the same client is recorded consistently across connections
+    let ip = req
+        .peer_addr()
+        .map(|addr| trusted_proxies.client_ip(addr.ip(), forwarded_for).to_string());
+
+    ClientInfo { user_agent, ip }
+}
+
+/// How the viewer authenticated
+enum Credentials {
+    Session(Session),
+    AccessToken(AccessToken),
+}
+
+/// Resolves the user and credentials behind the request's bearer token,
+/// either a session access token or a personal access token.
+/// Requests without a token are anonymous, invalid tokens and revoked credentials are rejected.
+async fn viewer(req: &HttpRequest, tokens: &TokenService, pool: Arc<Pool>) -> Result<Option<(User, Credentials)>, AppError> {
+    let token = match bearer_token(req) {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+
+    let credentials = if token.starts_with(ACCESS_TOKEN_PREFIX) {
+        let access_token = AccessTokenRepository::new(pool.clone())
+            .touch(&TokenService::digest(token))
+            .await?;
+
+        Credentials::AccessToken(access_token)
+    } else {
+        let claims = tokens.decode(token)?;
+
+        Credentials::Session(SessionRepository::new(pool.clone()).touch(claims.sid).await?)
+    };
+
+    let user_id = match &credentials {
+        Credentials::Session(session) => session.user_id,
+        Credentials::AccessToken(access_token) => access_token.user_id,
+    };
+
+    UserRepository::new(pool)
+        .get(user_id)
+        .await
+        .map(|user| Some((user, credentials)))
+        .map_err(|err| match err.error_type {
+            AppErrorType::NotFoundError => AppError {
+                message: Some("Invalid or expired access token".to_string()),
+                cause: err.cause,
+                error_type: AppErrorType::Unauthorized,
+                field: None,
+            },
+            _ => err,
+        })
+}
+
 async fn graphql(
+    req: HttpRequest,
     data: web::Json<GraphQLRequest>,
     schema: web::Data<Schema>,
     pool: web::Data<Pool>,
-    hashing_service: web::Data<HashingService>
-) -> HttpResponse {
+    hashing_service: web::Data<HashingService>,
+    token_service: web::Data<TokenService>,
+    notifier: web::Data<Notifier>,
+    comment_settings: web::Data<CommentSettings>,
+    account_settings: web::Data<AccountSettings>,
+    trusted_proxies: web::Data<TrustedProxies>,
+) -> Result<HttpResponse, AppError> {
     let pool: Arc<Pool> = pool.into_inner();
     let hashing = hashing_service.into_inner();
+    let tokens = token_service.into_inner();
+    let notifier = notifier.into_inner();
+    // A rejected token leaves the request anonymous, so an expired one can still refresh or log in.
+    // Resolvers that need a viewer report why it was rejected.
+    let (credentials, credentials_error) = match viewer(&req, &tokens, pool.clone()).await {
+        Ok(credentials) => (credentials, None),
+        Err(err) if err.error_type == AppErrorType::Unauthorized => (None, Some(err)),
+        Err(err) => return Err(err),
+    };
+    let (viewer, session, access_token) = match credentials {
+        Some((user, Credentials::Session(session))) => (Some(user), Some(session), None),
+        Some((user, Credentials::AccessToken(access_token))) => (Some(user), None, Some(access_token)),
+        None => (None, None, None),
+    };
+    let client = client_info(&req, &trusted_proxies);
     let post_loader = get_posts_loader(pool.clone());
-    let context: Context = Context { pool, hashing, post_loader };
+    let post_by_id_loader = get_post_by_id_loader(pool.clone());
+    let comments_loader = get_comments_loader(pool.clone());
+    let replies_loader = get_replies_loader(pool.clone());
+    let comment_by_id_loader = get_comment_by_id_loader(pool.clone());
+    let user_loader = get_users_loader(pool.clone());
+    let followers_loader = get_followers_loader(pool.clone());
+    let following_loader = get_following_loader(pool.clone());
+    let follower_count_loader = get_follower_count_loader(pool.clone());
+    let context: Context = Context {
+        pool,
+        hashing,
+        tokens,
+        notifier,
+        post_loader,
+        post_by_id_loader,
+        comments_loader,
+        replies_loader,
+        comment_by_id_loader,
+        comment_settings: *comment_settings.into_inner(),
+        account_settings: *account_settings.into_inner(),
+        user_loader,
+        followers_loader,
+        following_loader,
+        follower_count_loader,
+        viewer,
+        session,
+        access_token,
+        credentials_error,
+        signed_in: Arc::new(Mutex::new(None)),
+        client,
+    };
 
     let res = data.execute(&schema, &context).await;
 
-    HttpResponse::Ok().json(res)
+    Ok(HttpResponse::Ok().json(res))
 }
@@ -1,27 +1,139 @@
 /// Integration Tests
 
-use crate::config::Config;
+use crate::config::{AccountSettings, CommentSettings, Config, HashingService, TokenService, TrustedProxies};
 use crate::handlers::app_config;
-use actix_web::{test, App};
+use crate::mailer::Notifier;
+use crate::errors::AppErrorType;
+use crate::models::{login_attempt::{ACCOUNT_MAX_FAILURES, LOCKOUT_BASE_SECS}, post::{CreatePost, Post, UpdatePost}, user::{CreateUser, Role, User}};
+use crate::repositories::{comment::CommentRepository, post::PostRepository, user::UserRepository};
+use actix_web::{http::header, test, App};
 use deadpool_postgres::Pool;
 use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::{net::SocketAddr, sync::Arc};
+use uuid::Uuid;
+
+const PASSWORD: &str = "correct horse battery staple";
 
 /// Holds the configuration and connection pool for tests
 struct TestConfig {
-    _config: Config,
+    hashing_service: HashingService,
+    token_service: TokenService,
+    notifier: Notifier,
+    comment_settings: CommentSettings,
+    account_settings: AccountSettings,
+    trusted_proxies: TrustedProxies,
     pool: Pool,
 }
 
 lazy_static! {
     static ref CONFIG: TestConfig = {
-        let _config = Config::from_env().unwrap();
+        let config = Config::from_env().unwrap();
 
-        let pool = _config.configure_pool();
+        let pool = config.configure_pool();
 
-        TestConfig { _config, pool }
+        TestConfig {
+            hashing_service: config.hashing_service().unwrap(),
+            token_service: config.token_service(),
+            notifier: config.notifier().unwrap(),
+            comment_settings: config.comment_settings(),
+            account_settings: config.account_settings(),
+            trusted_proxies: config.trusted_proxies().unwrap(),
+            pool,
+        }
     };
 }
 
+/// Runs a GraphQL query against a fresh app and returns the JSON response
+async fn graphql(query: &str, variables: Value) -> Value {
+    graphql_as(None, query, variables).await
+}
+
+/// Runs a GraphQL query with an optional bearer token
+async fn graphql_as(token: Option<&str>, query: &str, variables: Value) -> Value {
+    graphql_from(None, token, query, variables).await
+}
+
+/// Runs a GraphQL query from an optional client address, with an optional bearer token
+async fn graphql_from(peer: Option<SocketAddr>, token: Option<&str>, query: &str, variables: Value) -> Value {
+    let app = App::new()
+        .data(CONFIG.pool.clone())
+        .data(CONFIG.hashing_service.clone())
+        .data(CONFIG.token_service.clone())
+        .data(CONFIG.notifier.clone())
+        .data(CONFIG.comment_settings)
+        .data(CONFIG.account_settings)
+        .data(CONFIG.trusted_proxies.clone())
+        .configure(app_config);
+
+    let mut app = test::init_service(app).await;
+
+    let mut req = test::TestRequest::post()
+        .uri("/graphql")
+        .set_json(&json!({ "query": query, "variables": variables }));
+
+    if let Some(token) = token {
+        req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    if let Some(peer) = peer {
+        req = req.peer_addr(peer);
+    }
+
+    test::read_response_json(&mut app, req.to_request()).await
+}
+
+/// Signs the user in and returns their access token
+async fn login(user: &User) -> String {
+    let res = graphql(
+        "mutation ($login: String!, $password: String!) { login(usernameOrEmail: $login, password: $password) { accessToken } }",
+        json!({ "login": user.username, "password": PASSWORD }),
+    )
+    .await;
+
+    res["data"]["login"]["accessToken"].as_str().unwrap().to_string()
+}
+
+fn unique_suffix() -> String {
+    Uuid::new_v4().to_simple().to_string()[..12].to_string()
+}
+
+/// Stores a new author straight through the repository
+async fn create_author() -> User {
+    let suffix = unique_suffix();
+
+    UserRepository::new(Arc::new(CONFIG.pool.clone()))
+        .create(
+            CreateUser {
+                username: format!("author_{}", suffix),
+                email: format!("author_{}@example.com", suffix),
+                password: PASSWORD.to_string(),
+                bio: None,
+                image: None,
+            },
+            Arc::new(CONFIG.hashing_service.clone()),
+        )
+        .await
+        .unwrap()
+}
+
+/// Stores a post by a new author, straight through the repositories
+async fn create_post() -> Post {
+    let author = create_author().await;
+
+    PostRepository::new(Arc::new(CONFIG.pool.clone()))
+        .create(
+            author.id,
+            CreatePost {
+                slug: Some(format!("hello-world-{}", unique_suffix())),
+                title: "Hello world".to_string(),
+                description: "First post".to_string(),
+                body: "Hello, world".to_string(),
+            },
+        )
+        .await
+        .unwrap()
+}
+
 #[actix_rt::test]
 async fn test_health() {
     let app = App::new().data(CONFIG.pool.clone()).configure(app_config);
@@ -34,3 +146,498 @@ async fn test_health() {
 
     assert_eq!(res.status(), 200, "GET / should return 200");
 }
+
+#[actix_rt::test]
+async fn test_invalid_token_is_anonymous() {
+    let author = create_author().await;
+
+    let res = graphql_as(
+        Some("expired"),
+        "mutation ($login: String!, $password: String!) { login(usernameOrEmail: $login, password: $password) { accessToken } }",
+        json!({ "login": author.username, "password": PASSWORD }),
+    )
+    .await;
+
+    assert!(res["data"]["login"]["accessToken"].is_string(), "A bad token shouldn't block logging in");
+
+    let res = graphql_as(Some("expired"), "mutation { logoutAllDevices }", json!({})).await;
+
+    assert_eq!(
+        res["errors"][0]["extensions"]["code"],
+        json!("UNAUTHORIZED"),
+        "Resolvers that need a viewer should report the bad token"
+    );
+}
+
+#[actix_rt::test]
+async fn test_login_reveals_own_private_fields() {
+    let author = create_author().await;
+
+    let res = graphql(
+        "mutation ($login: String!, $password: String!) { login(usernameOrEmail: $login, password: $password) { user { email role } } }",
+        json!({ "login": author.username, "password": PASSWORD }),
+    )
+    .await;
+
+    assert_eq!(res["data"]["login"]["user"]["email"], json!(author.email), "The signed in user should see their email");
+    assert_eq!(res["data"]["login"]["user"]["role"], json!("AUTHOR"), "The signed in user should see their role");
+}
+
+#[actix_rt::test]
+async fn test_admin_restores_deleted_user() {
+    let admin = create_author().await;
+    let user = create_author().await;
+
+    let client = CONFIG.pool.get().await.unwrap();
+    client
+        .execute("update users set role = 'admin' where id = $1", &[&admin.id])
+        .await
+        .unwrap();
+
+    let admin_token = login(&admin).await;
+
+    let res = graphql_as(Some(&admin_token), "mutation ($id: Uuid!) { deleteUser(id: $id) }", json!({ "id": user.id })).await;
+
+    assert_eq!(res["data"]["deleteUser"], json!(true), "Admins should delete accounts");
+
+    let res = graphql(
+        "mutation ($login: String!, $password: String!) { restoreAccount(usernameOrEmail: $login, password: $password) { accessToken } }",
+        json!({ "login": user.username, "password": PASSWORD }),
+    )
+    .await;
+
+    assert_eq!(
+        res["errors"][0]["extensions"]["code"],
+        json!("FORBIDDEN"),
+        "Owners should not undo an admin's deletion"
+    );
+
+    let res = graphql_as(Some(&admin_token), "mutation ($id: Uuid!) { restoreUser(id: $id) { id } }", json!({ "id": user.id })).await;
+
+    assert_eq!(res["data"]["restoreUser"]["id"], json!(user.id), "Admins should restore accounts");
+    assert!(!login(&user).await.is_empty(), "Restored users should log in again");
+}
+
+#[actix_rt::test]
+async fn test_access_tokens_cant_change_email() {
+    let user = create_author().await;
+
+    let res = graphql_as(
+        Some(&login(&user).await),
+        "mutation ($input: CreateAccessToken!) { createAccessToken(input: $input) { token } }",
+        json!({ "input": { "name": "script", "scopes": ["WRITE_PROFILE"] } }),
+    )
+    .await;
+
+    let token = res["data"]["createAccessToken"]["token"].as_str().unwrap().to_string();
+    let update = "mutation ($input: UpdateUser!) { updateUser(input: $input) { bio } }";
+
+    let res = graphql_as(Some(&token), update, json!({ "input": { "email": format!("taken_{}@example.com", unique_suffix()) } })).await;
+
+    assert_eq!(
+        res["errors"][0]["extensions"]["code"],
+        json!("FORBIDDEN"),
+        "Access tokens should not change the email"
+    );
+
+    let res = graphql_as(Some(&token), update, json!({ "input": { "bio": "Scripted" } })).await;
+
+    assert_eq!(res["data"]["updateUser"]["bio"], json!("Scripted"), "Access tokens should change the bio");
+}
+
+const LOGIN: &str = "mutation ($login: String!, $password: String!) { login(usernameOrEmail: $login, password: $password) { accessToken } }";
+
+/// A client address no other test uses
+fn unique_peer() -> SocketAddr {
+    let bytes = Uuid::new_v4();
+    let bytes = bytes.as_bytes();
+
+    SocketAddr::from(([10, bytes[0], bytes[1], bytes[2]], 40000))
+}
+
+/// The error code of a login attempt, or null if it succeeded
+async fn login_code(peer: SocketAddr, login: &str, password: &str) -> Value {
+    let res = graphql_from(Some(peer), None, LOGIN, json!({ "login": login, "password": password })).await;
+
+    res["errors"][0]["extensions"]["code"].clone()
+}
+
+#[actix_rt::test]
+async fn test_login_lockout() {
+    let user = create_author().await;
+    let peer = unique_peer();
+    let account_key = format!("account:{}", user.id);
+
+    for _ in 0..ACCOUNT_MAX_FAILURES {
+        assert_eq!(
+            login_code(peer, &user.username, "wrong password").await,
+            json!("UNAUTHORIZED"),
+            "Wrong passwords should be rejected until the limit"
+        );
+    }
+
+    assert_eq!(
+        login_code(peer, &user.email, PASSWORD).await,
+        json!("RATE_LIMITED"),
+        "Locked accounts should be rate limited, whichever login reaches them"
+    );
+
+    // Let the first lockout run out
+    let client = CONFIG.pool.get().await.unwrap();
+    client
+        .execute("update login_attempts set locked_until = current_timestamp - interval '1 second' where key = $1", &[&account_key])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        login_code(peer, &user.username, "wrong password").await,
+        json!("UNAUTHORIZED"),
+        "Expired lockouts should let attempts through"
+    );
+
+    let longer: bool = client
+        .query_one(
+            "select locked_until > current_timestamp + make_interval(secs => $2) as longer from login_attempts where key = $1",
+            &[&account_key, &(LOCKOUT_BASE_SECS * 1.5)],
+        )
+        .await
+        .unwrap()
+        .get("longer");
+
+    assert!(longer, "Failing again after a lockout should lock for longer");
+
+    let admin = create_author().await;
+    client
+        .execute("update users set role = 'admin' where id = $1", &[&admin.id])
+        .await
+        .unwrap();
+
+    let res = graphql_as(
+        Some(&login(&admin).await),
+        "mutation ($login: String!) { clearLoginLockout(usernameOrEmail: $login) }",
+        json!({ "login": user.username }),
+    )
+    .await;
+
+    assert_eq!(res["data"]["clearLoginLockout"], json!(true), "Admins should clear lockouts");
+    assert_eq!(login_code(unique_peer(), &user.username, PASSWORD).await, Value::Null, "Cleared accounts should log in");
+}
+
+#[actix_rt::test]
+async fn test_login_releases_ip_attempt() {
+    let user = create_author().await;
+    let peer = unique_peer();
+    let ip_key = format!("ip:{}", peer.ip());
+
+    assert_eq!(login_code(peer, &user.username, "wrong password").await, json!("UNAUTHORIZED"));
+    assert_eq!(login_code(peer, &user.username, PASSWORD).await, Value::Null, "The right password should log in");
+
+    let client = CONFIG.pool.get().await.unwrap();
+    let failures: i32 = client
+        .query_one("select failures from login_attempts where key = $1", &[&ip_key])
+        .await
+        .unwrap()
+        .get("failures");
+
+    assert_eq!(failures, 1, "Only the wrong password should count against the IP");
+}
+
+#[actix_rt::test]
+async fn test_post_by_id() {
+    let post = create_post().await;
+
+    let res = graphql(
+        "query ($id: Uuid!) { post(id: $id) { id slug title } }",
+        json!({ "id": post.id }),
+    )
+    .await;
+
+    assert_eq!(res["data"]["post"]["id"], json!(post.id), "post(id) should find the post");
+    assert_eq!(res["data"]["post"]["slug"], json!(post.slug), "post(id) should return its slug");
+}
+
+#[actix_rt::test]
+async fn test_post_by_slug() {
+    let post = create_post().await;
+
+    let res = graphql(
+        "query ($slug: String!) { postBySlug(slug: $slug) { id title } }",
+        json!({ "slug": post.slug }),
+    )
+    .await;
+
+    assert_eq!(res["data"]["postBySlug"]["id"], json!(post.id), "postBySlug should find the post");
+    assert_eq!(res["data"]["postBySlug"]["title"], json!(post.title), "postBySlug should return its title");
+}
+
+#[actix_rt::test]
+async fn test_post_by_unknown_slug() {
+    let res = graphql(
+        "query ($slug: String!) { postBySlug(slug: $slug) { id } }",
+        json!({ "slug": format!("missing-{}", Uuid::new_v4()) }),
+    )
+    .await;
+
+    assert_eq!(res["data"], Value::Null, "Unknown slugs should not return data");
+    assert_eq!(
+        res["errors"][0]["extensions"]["code"],
+        json!("NOT_FOUND"),
+        "Unknown slugs should be reported as not found"
+    );
+}
+
+#[actix_rt::test]
+async fn test_generated_slugs() {
+    let author = create_author().await;
+    let posts = PostRepository::new(Arc::new(CONFIG.pool.clone()));
+    let title = format!("Ünïcödé Title {}", unique_suffix());
+    let input = || CreatePost {
+        slug: None,
+        title: title.clone(),
+        description: "Generated slug".to_string(),
+        body: "Hello, world".to_string(),
+    };
+
+    let first = posts.create(author.id, input()).await.unwrap();
+    let second = posts.create(author.id, input()).await.unwrap();
+
+    assert!(first.slug.starts_with("unicode-title-"), "Slugs should be generated from the title");
+    assert_eq!(second.slug, format!("{}-2", first.slug), "Taken slugs should get a numbered suffix");
+
+    let explicit = posts
+        .create(author.id, CreatePost { slug: Some(first.slug.clone()), ..input() })
+        .await;
+
+    assert!(
+        explicit.err().map(|err| err.error_type) == Some(AppErrorType::Conflict),
+        "Taken explicit slugs should be a conflict"
+    );
+}
+
+#[actix_rt::test]
+async fn test_post_by_old_slug() {
+    let post = create_post().await;
+    let new_slug = format!("renamed-{}", unique_suffix());
+
+    let client = CONFIG.pool.get().await.unwrap();
+    client
+        .execute("update posts set slug = $2 where id = $1", &[&post.id, &new_slug])
+        .await
+        .unwrap();
+
+    let res = graphql(
+        "query ($slug: String!) { postBySlug(slug: $slug) { id slug } }",
+        json!({ "slug": post.slug }),
+    )
+    .await;
+
+    assert_eq!(res["data"]["postBySlug"]["id"], json!(post.id), "Old slugs should still find the post");
+    assert_eq!(res["data"]["postBySlug"]["slug"], json!(new_slug), "The canonical slug should be returned");
+}
+
+/// Renames the post straight through the repository, its current slug goes to the history
+async fn rename_post(post: &Post) -> Post {
+    PostRepository::new(Arc::new(CONFIG.pool.clone()))
+        .update(
+            post.id,
+            UpdatePost {
+                slug: Some(format!("renamed-{}", unique_suffix())),
+                title: None,
+                description: None,
+                body: None,
+            },
+        )
+        .await
+        .unwrap()
+}
+
+#[actix_rt::test]
+async fn test_old_slugs_stay_reserved() {
+    let post = create_post().await;
+    rename_post(&post).await;
+
+    let author = create_author().await;
+    let posts = PostRepository::new(Arc::new(CONFIG.pool.clone()));
+    let input = |slug| CreatePost {
+        slug,
+        title: post.slug.clone(),
+        description: "Second post".to_string(),
+        body: "Hello again".to_string(),
+    };
+
+    let explicit = posts.create(author.id, input(Some(post.slug.clone()))).await;
+
+    assert!(
+        explicit.err().map(|err| err.error_type) == Some(AppErrorType::Conflict),
+        "Another post's old slug should be a conflict"
+    );
+
+    let generated = posts.create(author.id, input(None)).await.unwrap();
+
+    assert_eq!(generated.slug, format!("{}-2", post.slug), "Generated slugs should skip other posts' old slugs");
+
+    let res = graphql(
+        "query ($slug: String!) { postBySlug(slug: $slug) { id } }",
+        json!({ "slug": post.slug }),
+    )
+    .await;
+
+    assert_eq!(res["data"]["postBySlug"]["id"], json!(post.id), "The old slug should still find its post");
+}
+
+#[actix_rt::test]
+async fn test_update_to_old_slug() {
+    let first = create_post().await;
+    rename_post(&first).await;
+
+    let second = create_post().await;
+    let author = UserRepository::new(Arc::new(CONFIG.pool.clone())).get(second.author_id).await.unwrap();
+
+    let res = graphql_as(
+        Some(&login(&author).await),
+        "mutation ($id: Uuid!, $input: UpdatePost!) { updatePost(id: $id, input: $input) { slug } }",
+        json!({ "id": second.id, "input": { "slug": first.slug } }),
+    )
+    .await;
+
+    assert_eq!(
+        res["errors"][0]["extensions"]["code"],
+        json!("CONFLICT"),
+        "Taking another post's old slug should be a conflict"
+    );
+    assert_eq!(res["errors"][0]["extensions"]["field"], json!("slug"), "The conflict should name the slug");
+
+    let res = graphql(
+        "query ($slug: String!) { postBySlug(slug: $slug) { id } }",
+        json!({ "slug": first.slug }),
+    )
+    .await;
+
+    assert_eq!(res["data"]["postBySlug"]["id"], json!(first.id), "The old slug should still find its post");
+}
+
+#[actix_rt::test]
+async fn test_new_accounts_post_once_verified() {
+    let author = create_author().await;
+    let token = login(&author).await;
+    let create = "mutation ($input: CreatePost!) { createPost(input: $input) { id } }";
+    let input = json!({ "input": { "title": "Hello world", "description": "First post", "body": "Hello, world" } });
+
+    assert_eq!(author.role, Role::Author, "New accounts should be authors");
+
+    let res = graphql_as(Some(&token), create, input.clone()).await;
+
+    assert_eq!(
+        res["errors"][0]["extensions"]["code"],
+        json!("FORBIDDEN"),
+        "Unverified accounts should not post"
+    );
+
+    let client = CONFIG.pool.get().await.unwrap();
+    client
+        .execute("update users set email_verified = true where id = $1", &[&author.id])
+        .await
+        .unwrap();
+
+    let res = graphql_as(Some(&token), create, input).await;
+
+    assert!(res["data"]["createPost"]["id"].is_string(), "Verified new accounts should post");
+}
+
+#[actix_rt::test]
+async fn test_update_and_delete_post() {
+    let post = create_post().await;
+    let author = UserRepository::new(Arc::new(CONFIG.pool.clone())).get(post.author_id).await.unwrap();
+    let author_token = login(&author).await;
+    let stranger_token = login(&create_author().await).await;
+
+    let update = "mutation ($id: Uuid!, $input: UpdatePost!) { updatePost(id: $id, input: $input) { title slug body } }";
+    let input = json!({ "id": post.id, "input": { "title": "Hello again" } });
+
+    let res = graphql_as(Some(&stranger_token), update, input.clone()).await;
+
+    assert_eq!(
+        res["errors"][0]["extensions"]["code"],
+        json!("FORBIDDEN"),
+        "Only the author or a moderator should update a post"
+    );
+
+    let res = graphql_as(Some(&author_token), update, input).await;
+
+    assert_eq!(res["data"]["updatePost"]["title"], json!("Hello again"), "The title should change");
+    assert_eq!(res["data"]["updatePost"]["slug"], json!(post.slug), "Fields left out should be kept");
+    assert_eq!(res["data"]["updatePost"]["body"], json!(post.body), "Fields left out should be kept");
+
+    let res = graphql_as(
+        Some(&author_token),
+        "mutation ($id: Uuid!) { deletePost(id: $id) }",
+        json!({ "id": post.id }),
+    )
+    .await;
+
+    assert_eq!(res["data"]["deletePost"], json!(true), "The author should delete the post");
+
+    let res = graphql("query ($id: Uuid!) { post(id: $id) { id } }", json!({ "id": post.id })).await;
+
+    assert_eq!(
+        res["errors"][0]["extensions"]["code"],
+        json!("NOT_FOUND"),
+        "Deleted posts should be gone"
+    );
+}
+
+#[actix_rt::test]
+async fn test_deleted_comment_promotes_replies() {
+    let post = create_post().await;
+    let author = UserRepository::new(Arc::new(CONFIG.pool.clone())).get(post.author_id).await.unwrap();
+
+    let client = CONFIG.pool.get().await.unwrap();
+    client
+        .execute("update users set email_verified = true where id = $1", &[&author.id])
+        .await
+        .unwrap();
+
+    let token = login(&author).await;
+    let create = "mutation ($input: CreateComment!) { createComment(input: $input) { id depth } }";
+    let reply = |parent_id: Option<Value>| {
+        json!({ "input": { "postId": post.id, "parentId": parent_id, "body": "Hello" } })
+    };
+
+    let res = graphql_as(Some(&token), create, reply(None)).await;
+    let top = res["data"]["createComment"]["id"].clone();
+    let res = graphql_as(Some(&token), create, reply(Some(top.clone()))).await;
+    let child = res["data"]["createComment"]["id"].clone();
+    let res = graphql_as(Some(&token), create, reply(Some(child.clone()))).await;
+    let grandchild = res["data"]["createComment"]["id"].clone();
+
+    let res = graphql_as(
+        Some(&token),
+        "mutation ($id: Uuid!) { deleteComment(id: $id) }",
+        json!({ "id": top }),
+    )
+    .await;
+
+    assert_eq!(res["data"]["deleteComment"], json!(true), "The author should delete the comment");
+
+    let comments = CommentRepository::new(Arc::new(CONFIG.pool.clone()));
+    let promoted = comments.get(serde_json::from_value(child).unwrap()).await.unwrap();
+
+    assert_eq!(promoted.parent_id, None, "Replies should move up to the top level");
+    assert_eq!(promoted.depth, 0, "Promoted replies should be top level");
+
+    let nested = comments.get(serde_json::from_value(grandchild.clone()).unwrap()).await.unwrap();
+
+    assert_eq!(nested.depth, 1, "Nested replies should move up with their parent");
+
+    let mut parent = grandchild;
+    for depth in 2..=CONFIG.comment_settings.max_depth {
+        let res = graphql_as(Some(&token), create, reply(Some(parent))).await;
+
+        assert_eq!(res["data"]["createComment"]["depth"], json!(depth), "Promoted threads should nest as deep as new ones");
+        parent = res["data"]["createComment"]["id"].clone();
+    }
+
+    let res = graphql_as(Some(&token), create, reply(Some(parent))).await;
+
+    assert_eq!(res["errors"][0]["extensions"]["code"], json!("VALIDATION_ERROR"), "The nesting limit should still apply");
+}
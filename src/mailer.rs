@@ -0,0 +1,222 @@
+/// Outgoing email
+/// Transports implement `Mailer`, `Notifier` renders the messages we send to users
+
+use actix_web::web;
+use async_trait::async_trait;
+use lettre::{smtp::authentication::Credentials, ClientSecurity, ClientTlsParameters, SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use native_tls::TlsConnector;
+use slog_scope::info;
+use std::{fs::OpenOptions, io::Write, path::PathBuf, sync::Arc};
+
+use crate::errors::{AppError, AppErrorType};
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), AppError>;
+}
+
+fn delivery_error(cause: String) -> AppError {
+    AppError {
+        message: Some("Error sending email.".to_string()),
+        cause: Some(cause),
+        error_type: AppErrorType::DbError,
+        field: None,
+    }
+}
+
+/// Logs emails instead of delivering them, for development
+pub struct StdoutMailer {
+    pub from: String,
+}
+
+#[async_trait]
+impl Mailer for StdoutMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        info!("Email from {} to {}: {}\n{}", self.from, email.to, email.subject, email.body);
+        Ok(())
+    }
+}
+
+/// Appends emails to a file, for development and tests
+pub struct FileMailer {
+    pub from: String,
+    pub path: PathBuf,
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|err| delivery_error(err.to_string()))?;
+
+        writeln!(
+            file,
+            "From: {}\nTo: {}\nSubject: {}\n\n{}\n",
+            self.from, email.to, email.subject, email.body
+        )
+        .map_err(|err| delivery_error(err.to_string()))
+    }
+}
+
+#[derive(Clone)]
+pub struct SmtpMailer {
+    pub from: String,
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    pub credentials: Option<(String, String)>,
+}
+
+impl SmtpMailer {
+    fn deliver(&self, email: Email) -> Result<(), String> {
+        let message = EmailBuilder::new()
+            .from(self.from.as_str())
+            .to(email.to.as_str())
+            .subject(email.subject)
+            .text(email.body)
+            .build()
+            .map_err(|err| err.to_string())?;
+
+        // STARTTLS on the configured port, checking the certificate against the host name
+        let security = match self.tls {
+            true => {
+                let connector = TlsConnector::new().map_err(|err| err.to_string())?;
+                ClientSecurity::Required(ClientTlsParameters::new(self.host.clone(), connector))
+            }
+            false => ClientSecurity::None,
+        };
+
+        let client = SmtpClient::new((self.host.as_str(), self.port), security).map_err(|err| err.to_string())?;
+
+        let client = match &self.credentials {
+            Some((username, password)) => client.credentials(Credentials::new(username.clone(), password.clone())),
+            None => client,
+        };
+
+        client
+            .transport()
+            .send(message.into())
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        let mailer = self.clone();
+
+        // lettre's SMTP transport is blocking
+        web::block(move || mailer.deliver(email))
+            .await
+            .map_err(|err| delivery_error(err.to_string()))
+    }
+}
+
+/// Renders the emails sent to users and hands them to the configured mailer
+#[derive(Clone)]
+pub struct Notifier {
+    mailer: Arc<dyn Mailer>,
+    base_url: String,
+}
+
+impl Notifier {
+    pub fn new(mailer: Arc<dyn Mailer>, base_url: String) -> Notifier {
+        Notifier { mailer, base_url }
+    }
+
+    pub async fn password_reset(&self, to: &str, token: &str) -> Result<(), AppError> {
+        self.mailer
+            .send(Email {
+                to: to.to_string(),
+                subject: "Reset your password".to_string(),
+                body: format!(
+                    "Someone asked to reset the password of your account.\n\
+                     Follow this link to choose a new one: {}/reset-password?token={}\n\n\
+                     If it wasn't you, you can ignore this email.",
+                    self.base_url, token
+                ),
+            })
+            .await
+    }
+
+    pub async fn email_verification(&self, to: &str, token: &str) -> Result<(), AppError> {
+        self.mailer
+            .send(Email {
+                to: to.to_string(),
+                subject: "Verify your email address".to_string(),
+                body: format!(
+                    "Welcome! Follow this link to verify your email address: {}/verify-email?token={}",
+                    self.base_url, token
+                ),
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Email, FileMailer, Mailer, SmtpMailer};
+    use std::{env, fs};
+
+    #[actix_rt::test]
+    async fn test_file_mailer() {
+        let path = env::temp_dir().join(format!("mailer-{}.txt", uuid::Uuid::new_v4()));
+
+        let mailer = FileMailer {
+            from: "no-reply@example.com".to_string(),
+            path: path.clone(),
+        };
+
+        mailer
+            .send(Email {
+                to: "user@example.com".to_string(),
+                subject: "Hello".to_string(),
+                body: "Test body".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).ok();
+
+        assert!(contents.contains("To: user@example.com"), "Recipient should be written");
+        assert!(contents.contains("Subject: Hello"), "Subject should be written");
+        assert!(contents.contains("Test body"), "Body should be written");
+    }
+
+    /// Needs an SMTP sink listening on localhost:1025, e.g. `docker-compose up -d mailhog`
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_smtp_mailer() {
+        let mailer = SmtpMailer {
+            from: "no-reply@example.com".to_string(),
+            host: "127.0.0.1".to_string(),
+            port: 1025,
+            tls: false,
+            credentials: None,
+        };
+
+        let result = mailer
+            .send(Email {
+                to: "user@example.com".to_string(),
+                subject: "Hello".to_string(),
+                body: "Test body".to_string(),
+            })
+            .await;
+
+        assert!(result.is_ok(), "Email should be accepted by the SMTP sink");
+    }
+}
@@ -2,25 +2,56 @@ mod config;
 mod handlers;
 mod models;
 mod errors;
+mod mailer;
 mod repositories;
+mod validation;
 
 use crate::config::Config;
 use crate::handlers::app_config;
+use crate::repositories::user::UserRepository;
 use actix_cors::Cors;
 use actix_web::{http::header, http::Method, middleware, App, HttpServer};
+use deadpool_postgres::Pool;
+use slog_scope::{error, info};
+use std::{sync::Arc, time::Duration};
+
+/// Periodically removes deleted accounts whose grace period is over
+async fn purge_deleted_accounts(pool: Arc<Pool>, grace_period: i64) {
+    let mut interval = actix_rt::time::interval(Duration::from_secs(60 * 60));
+
+    loop {
+        interval.tick().await;
+
+        match UserRepository::new(pool.clone()).purge_deleted(grace_period).await {
+            Ok(purged) if purged > 0 => info!("Purged {} deleted accounts", purged),
+            Ok(_) => {}
+            Err(err) => error!("Error purging deleted accounts. {}", err; "cause" => err.cause.clone().unwrap_or_default()),
+        }
+    }
+}
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     let config = Config::from_env().unwrap();
 
     let pool = config.configure_pool();
-    let hashing_service = config.hashing_service();
+    let hashing_service = config.hashing_service().unwrap();
+    let token_service = config.token_service();
+    let notifier = config.notifier().unwrap();
+    let comment_settings = config.comment_settings();
+    let account_settings = config.account_settings();
+    let trusted_proxies = config.trusted_proxies().unwrap();
 
     let host = config.server.host;
     let port = config.server.port;
     let server_address = format!("{}:{}", host, port);
     let server_url = config.server.url;
 
+    actix_rt::spawn(purge_deleted_accounts(
+        Arc::new(pool.clone()),
+        account_settings.deletion_grace_period,
+    ));
+
     HttpServer::new(move || {
         let cors = Cors::new()
             .allowed_origin(&server_url)
@@ -35,6 +66,11 @@ async fn main() -> std::io::Result<()> {
             .wrap(middleware::Logger::default())
             .data(pool.clone())
             .data(hashing_service.clone())
+            .data(token_service.clone())
+            .data(notifier.clone())
+            .data(comment_settings)
+            .data(account_settings)
+            .data(trusted_proxies.clone())
             .configure(app_config)
     })
     .bind(server_address)?
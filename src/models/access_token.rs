@@ -0,0 +1,77 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use tokio_pg_mapper_derive::PostgresMapper;
+use juniper::{GraphQLEnum, GraphQLInputObject};
+use std::{error::Error, str::FromStr};
+use tokio_postgres::types::{FromSql, Type};
+
+/// Prefix telling personal access tokens apart from session access tokens
+pub const ACCESS_TOKEN_PREFIX: &str = "pat_";
+
+/// What a personal access token can change on behalf of its user, reads are always allowed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, GraphQLEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    WritePosts,
+    WriteComments,
+    /// Bio, image and follows, never the username or email
+    WriteProfile,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::WritePosts => "write_posts",
+            Scope::WriteComments => "write_comments",
+            Scope::WriteProfile => "write_profile",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(scope: &str) -> Result<Scope, String> {
+        match scope {
+            "write_posts" => Ok(Scope::WritePosts),
+            "write_comments" => Ok(Scope::WriteComments),
+            "write_profile" => Ok(Scope::WriteProfile),
+            _ => Err(format!("Unknown scope {}", scope)),
+        }
+    }
+}
+
+impl<'a> FromSql<'a> for Scope {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Scope, Box<dyn Error + Sync + Send>> {
+        let scope = <&str as FromSql>::from_sql(ty, raw)?;
+        scope.parse().map_err(|err: String| err.into())
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&str as FromSql>::accepts(ty)
+    }
+}
+
+/// Long-lived token for scripts, only its digest is stored
+#[derive(Clone, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "access_tokens")]
+pub struct AccessToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct CreateAccessToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Leave out for a token that doesn't expire
+    pub expires_in_days: Option<i32>,
+}
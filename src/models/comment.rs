@@ -2,6 +2,7 @@ use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use tokio_pg_mapper_derive::PostgresMapper;
+use juniper::{GraphQLEnum, GraphQLInputObject};
 
 #[derive(Clone, Serialize, Deserialize, PostgresMapper)]
 #[pg_mapper(table = "comments")]
@@ -12,4 +13,82 @@ pub struct Comment {
     pub body: String,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    /// The comment this one replies to, top level comments have none
+    pub parent_id: Option<Uuid>,
+    pub depth: i32,
+    /// Direct replies, computed when the comment is queried
+    pub reply_count: i64,
+}
+
+/// How a list of comments is sorted
+#[derive(Debug, Clone, Copy, PartialEq, GraphQLEnum)]
+pub enum CommentOrder {
+    Oldest,
+    Newest,
+    /// Most replied to first
+    Top,
+}
+
+impl CommentOrder {
+    /// Sorts comments that are already ordered from oldest to newest
+    pub fn sort(&self, comments: &mut Vec<Comment>) {
+        match self {
+            CommentOrder::Oldest => {}
+            CommentOrder::Newest => comments.reverse(),
+            // Stable, so ties stay oldest first
+            CommentOrder::Top => comments.sort_by(|a, b| b.reply_count.cmp(&a.reply_count)),
+        }
+    }
+}
+
+#[derive(GraphQLInputObject)]
+pub struct CreateComment {
+    pub post_id: Uuid,
+    /// Comment to reply to, on the same post
+    pub parent_id: Option<Uuid>,
+    pub body: String,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct UpdateComment {
+    pub body: String,
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Comment, CommentOrder};
+    use chrono::NaiveDate;
+    use uuid::Uuid;
+
+    fn comment(minute: u32, reply_count: i64) -> Comment {
+        let created_at = NaiveDate::from_ymd(2020, 7, 18).and_hms(12, minute, 0);
+
+        Comment {
+            id: Uuid::new_v4(),
+            author_id: Uuid::new_v4(),
+            post_id: Uuid::new_v4(),
+            body: "Nice post".to_string(),
+            created_at,
+            updated_at: created_at,
+            parent_id: None,
+            depth: 0,
+            reply_count,
+        }
+    }
+
+    fn minutes(order: CommentOrder) -> Vec<u32> {
+        use chrono::Timelike;
+
+        let mut comments = vec![comment(0, 1), comment(1, 3), comment(2, 0), comment(3, 3)];
+        order.sort(&mut comments);
+        comments.iter().map(|comment| comment.created_at.minute()).collect()
+    }
+
+    #[test]
+    fn test_comment_order() {
+        assert_eq!(minutes(CommentOrder::Oldest), vec![0, 1, 2, 3], "Oldest should keep the query order");
+        assert_eq!(minutes(CommentOrder::Newest), vec![3, 2, 1, 0], "Newest should reverse it");
+        assert_eq!(minutes(CommentOrder::Top), vec![1, 3, 0, 2], "Top should sort by replies, oldest first on ties");
+    }
 }
@@ -0,0 +1,87 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use tokio_pg_mapper_derive::PostgresMapper;
+use uuid::Uuid;
+
+use super::user::normalize_login;
+
+/// Failures allowed for an account before it gets locked out
+pub const ACCOUNT_MAX_FAILURES: i32 = 5;
+/// Failures allowed from a single IP, higher since many users can share one
+pub const IP_MAX_FAILURES: i32 = 20;
+/// First lockout length, each further failure doubles it
+pub const LOCKOUT_BASE_SECS: f64 = 30.0;
+pub const LOCKOUT_MAX_SECS: f64 = 24.0 * 60.0 * 60.0;
+
+/// What failed logins are counted against
+#[derive(Debug, Clone, PartialEq)]
+pub enum ThrottleKey {
+    /// An existing account, whichever username or email was used to reach it
+    Account(Uuid),
+    /// A login that doesn't match any account
+    Login(String),
+    Ip(String),
+}
+
+impl ThrottleKey {
+    /// Logins are normalized and case insensitive, so are their lockouts
+    pub fn login(username_or_email: &str) -> ThrottleKey {
+        ThrottleKey::Login(normalize_login(username_or_email).to_lowercase())
+    }
+
+    pub fn key(&self) -> String {
+        match self {
+            ThrottleKey::Account(id) => format!("account:{}", id),
+            ThrottleKey::Login(login) => format!("login:{}", login),
+            ThrottleKey::Ip(ip) => format!("ip:{}", ip),
+        }
+    }
+
+    pub fn max_failures(&self) -> i32 {
+        match self {
+            ThrottleKey::Account(_) | ThrottleKey::Login(_) => ACCOUNT_MAX_FAILURES,
+            ThrottleKey::Ip(_) => IP_MAX_FAILURES,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "login_attempts")]
+pub struct LoginAttempt {
+    pub key: String,
+    pub failures: i32,
+    pub last_failed_at: NaiveDateTime,
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::ThrottleKey;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_login_key_ignores_case() {
+        assert_eq!(
+            ThrottleKey::login(" Jane@Example.com ").key(),
+            ThrottleKey::login("jane@example.com").key(),
+            "Login keys should ignore case and surrounding spaces"
+        );
+    }
+
+    #[test]
+    fn test_keys_dont_collide() {
+        let id = Uuid::new_v4();
+
+        assert_ne!(
+            ThrottleKey::login("127.0.0.1").key(),
+            ThrottleKey::Ip("127.0.0.1".to_string()).key(),
+            "Login and IP keys should never collide"
+        );
+        assert_ne!(
+            ThrottleKey::Account(id).key(),
+            ThrottleKey::login(&id.to_string()).key(),
+            "Account and login keys should never collide"
+        );
+    }
+}
@@ -1,3 +1,7 @@
+pub mod access_token;
 pub mod comment;
+pub mod login_attempt;
 pub mod post;
-pub mod user;
\ No newline at end of file
+pub mod session;
+pub mod user;
+pub mod user_token;
\ No newline at end of file
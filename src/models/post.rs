@@ -2,9 +2,16 @@ use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use tokio_pg_mapper_derive::PostgresMapper;
-use juniper::{GraphQLObject, GraphQLInputObject};
+use juniper::GraphQLInputObject;
+use deunicode::deunicode;
 
-#[derive(Clone, Serialize, Deserialize, PostgresMapper, GraphQLObject)]
+pub const SLUG_MAX_LENGTH: usize = 100;
+/// Generated slugs tried before giving up on a title
+pub const SLUG_ATTEMPTS: u32 = 6;
+/// Numbered suffixes tried before falling back to random ones
+const SLUG_NUMBERED_ATTEMPTS: u32 = 3;
+
+#[derive(Clone, Serialize, Deserialize, PostgresMapper)]
 #[pg_mapper(table = "posts")]
 pub struct Post {
     pub id: Uuid,
@@ -19,9 +26,106 @@ pub struct Post {
 
 #[derive(GraphQLInputObject)]
 pub struct CreatePost {
-    pub author_id: Uuid,
     pub slug: Option<String>,
     pub title: String,
     pub description: String,
     pub body: String,
-}
\ No newline at end of file
+}
+
+/// Post changes, fields left out are kept as they are
+#[derive(GraphQLInputObject)]
+pub struct UpdatePost {
+    pub slug: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub body: Option<String>,
+}
+
+/// Lowercase ASCII letters and numbers, separated by single dashes
+pub fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= SLUG_MAX_LENGTH
+        && slug.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !slug.starts_with('-')
+        && !slug.ends_with('-')
+        && !slug.contains("--")
+}
+
+/// Cuts the slug down to at most `max` characters, without leaving a dash at the end
+fn truncate_slug(slug: &str, max: usize) -> String {
+    slug.chars().take(max).collect::<String>().trim_end_matches('-').to_string()
+}
+
+/// Transliterated, lowercase, hyphenated slug for a title
+pub fn slugify(title: &str) -> String {
+    let mut slug = String::with_capacity(title.len());
+
+    for c in deunicode(title).to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    match truncate_slug(&slug, SLUG_MAX_LENGTH) {
+        slug if slug.is_empty() => "post".to_string(),
+        slug => slug,
+    }
+}
+
+/// Slug to try on the given attempt: the base slug first,
+/// then numbered suffixes and finally short random ones
+pub fn slug_candidate(base: &str, attempt: u32) -> String {
+    let suffix = match attempt {
+        0 => return base.to_string(),
+        n if n <= SLUG_NUMBERED_ATTEMPTS => (n + 1).to_string(),
+        _ => Uuid::new_v4().to_simple().to_string()[..6].to_string(),
+    };
+
+    format!("{}-{}", truncate_slug(base, SLUG_MAX_LENGTH - suffix.len() - 1), suffix)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{is_valid_slug, slug_candidate, slugify, SLUG_MAX_LENGTH};
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Hello, World!"), "hello-world", "Punctuation should become single dashes");
+        assert_eq!(slugify("  Crème brûlée à la Rust  "), "creme-brulee-a-la-rust", "Accents should be transliterated");
+        assert_eq!(slugify("Привет мир"), "privet-mir", "Other scripts should be transliterated");
+        assert_eq!(slugify("?!"), "post", "Titles without letters should still get a slug");
+    }
+
+    #[test]
+    fn test_slugify_length() {
+        let slug = slugify(&"word ".repeat(50));
+
+        assert!(slug.len() <= SLUG_MAX_LENGTH, "Slugs should be truncated");
+        assert!(is_valid_slug(&slug), "Truncated slugs should not end with a dash");
+    }
+
+    #[test]
+    fn test_slug_candidates() {
+        let base = "a".repeat(SLUG_MAX_LENGTH);
+
+        assert_eq!(slug_candidate("hello-world", 0), "hello-world", "The base slug should be tried first");
+        assert_eq!(slug_candidate("hello-world", 1), "hello-world-2", "Numbered suffixes should follow");
+        assert!(slug_candidate("hello-world", 5).starts_with("hello-world-"), "Random suffixes should keep the base");
+
+        for attempt in 0..6 {
+            assert!(is_valid_slug(&slug_candidate(&base, attempt)), "Candidates should be valid slugs");
+        }
+    }
+
+    #[test]
+    fn test_is_valid_slug() {
+        assert!(is_valid_slug("hello-world-2"), "Lowercase words and numbers should be valid");
+
+        for slug in &["", "Hello", "hello world", "-hello", "hello-", "hello--world", "héllo"] {
+            assert!(!is_valid_slug(slug), "{:?} should be invalid", slug);
+        }
+    }
+}
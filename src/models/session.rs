@@ -0,0 +1,20 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use tokio_pg_mapper_derive::PostgresMapper;
+
+/// A signed-in device.
+/// Holds the hash of the refresh token used to rotate its access tokens.
+#[derive(Clone, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "sessions")]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub refresh_token_hash: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
@@ -5,7 +5,127 @@ use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use tokio_pg_mapper_derive::PostgresMapper;
-use juniper::{GraphQLInputObject};
+use juniper::{GraphQLEnum, GraphQLInputObject};
+use std::{error::Error, str::FromStr};
+use tokio_postgres::types::{FromSql, Type};
+use unicode_normalization::UnicodeNormalization;
+
+pub const USERNAME_MIN_LENGTH: usize = 3;
+pub const USERNAME_MAX_LENGTH: usize = 32;
+pub const EMAIL_MAX_LENGTH: usize = 254;
+
+/// Usernames that could be mistaken for the site itself or clash with routes
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin", "administrator", "anonymous", "api", "graphiql", "graphql", "me",
+    "moderator", "null", "root", "settings", "support", "system",
+];
+
+/// What a user is allowed to do, each role includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, GraphQLEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Reader,
+    Author,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Reader => "reader",
+            Role::Author => "author",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Role, String> {
+        match role {
+            "reader" => Ok(Role::Reader),
+            "author" => Ok(Role::Author),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role {}", role)),
+        }
+    }
+}
+
+impl<'a> FromSql<'a> for Role {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Role, Box<dyn Error + Sync + Send>> {
+        let role = <&str as FromSql>::from_sql(ty, raw)?;
+        role.parse().map_err(|err: String| err.into())
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&str as FromSql>::accepts(ty)
+    }
+}
+
+/// Folds compatibility characters, e.g. fullwidth letters, into their plain form
+pub fn normalize_login(username_or_email: &str) -> String {
+    username_or_email.nfkc().collect::<String>().trim().to_string()
+}
+
+/// Normalized username, or why it isn't allowed.
+/// Case is kept for display, uniqueness ignores it.
+pub fn normalize_username(username: &str) -> Result<String, String> {
+    let username = normalize_login(username);
+    let length = username.chars().count();
+
+    if length < USERNAME_MIN_LENGTH || length > USERNAME_MAX_LENGTH {
+        return Err(format!(
+            "Username must be between {} and {} characters long",
+            USERNAME_MIN_LENGTH, USERNAME_MAX_LENGTH
+        ));
+    }
+
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err("Username can only contain letters, numbers, underscores and dashes".to_string());
+    }
+
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err("Username must start with a letter or a number".to_string());
+    }
+
+    if RESERVED_USERNAMES.contains(&username.to_lowercase().as_str()) {
+        return Err(format!("Username {} is reserved", username));
+    }
+
+    Ok(username)
+}
+
+/// Normalized email address, or why it isn't valid.
+/// Domains are case insensitive so they are lowercased, uniqueness ignores case altogether.
+pub fn normalize_email(email: &str) -> Result<String, String> {
+    let email = normalize_login(email);
+    let invalid = || Err("Invalid email address".to_string());
+
+    if email.len() > EMAIL_MAX_LENGTH || email.chars().any(char::is_whitespace) {
+        return invalid();
+    }
+
+    let mut parts = email.rsplitn(2, '@');
+    let (domain, local) = match (parts.next(), parts.next()) {
+        (Some(domain), Some(local)) => (domain, local),
+        _ => return invalid(),
+    };
+
+    let valid_domain = domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !domain.contains("..");
+
+    if local.is_empty() || local.contains('@') || !valid_domain {
+        return invalid();
+    }
+
+    Ok(format!("{}@{}", local, domain.to_lowercase()))
+}
 
 #[derive(Clone, Serialize, Deserialize, PostgresMapper)]
 #[pg_mapper(table = "users")]
@@ -18,6 +138,12 @@ pub struct User {
     pub image: Option<String>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub email_verified: bool,
+    pub password_key_id: String,
+    pub role: Role,
+    pub deleted_at: Option<NaiveDateTime>,
+    /// The user who deleted the account, the owner or an admin
+    pub deleted_by: Option<Uuid>,
 }
 
 #[derive(GraphQLInputObject)]
@@ -28,3 +154,66 @@ pub struct CreateUser {
     pub bio: Option<String>,
     pub image: Option<String>,
 }
+
+/// Profile changes, fields left out are kept as they are
+#[derive(GraphQLInputObject)]
+pub struct UpdateUser {
+    pub username: Option<String>,
+    pub email: Option<String>,
+    /// An empty string clears the bio
+    pub bio: Option<String>,
+    /// An empty string clears the image
+    pub image: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{normalize_email, normalize_username, Role};
+
+    #[test]
+    fn test_role_order() {
+        assert!(Role::Admin > Role::Moderator, "Admins should outrank moderators");
+        assert!(Role::Moderator > Role::Author, "Moderators should outrank authors");
+        assert!(Role::Author > Role::Reader, "Authors should outrank readers");
+    }
+
+    #[test]
+    fn test_role_round_trip() {
+        for role in &[Role::Reader, Role::Author, Role::Moderator, Role::Admin] {
+            assert_eq!(role.as_str().parse::<Role>(), Ok(*role), "Role should parse back");
+        }
+
+        assert!("owner".parse::<Role>().is_err(), "Unknown roles should be rejected");
+    }
+
+    #[test]
+    fn test_normalize_username() {
+        assert_eq!(normalize_username(" Alice_92 "), Ok("Alice_92".to_string()), "Surrounding spaces should be trimmed");
+        assert_eq!(
+            normalize_username("\u{ff21}lice"),
+            Ok("Alice".to_string()),
+            "Fullwidth letters should be folded"
+        );
+
+        assert!(normalize_username("al").is_err(), "Short usernames should be rejected");
+        assert!(normalize_username(&"a".repeat(33)).is_err(), "Long usernames should be rejected");
+        assert!(normalize_username("al ice").is_err(), "Spaces should be rejected");
+        assert!(normalize_username("_alice").is_err(), "Usernames should start with a letter or number");
+        assert!(normalize_username("\u{430}lice").is_err(), "Lookalike letters should be rejected");
+        assert!(normalize_username("Admin").is_err(), "Reserved usernames should be rejected regardless of case");
+    }
+
+    #[test]
+    fn test_normalize_email() {
+        assert_eq!(
+            normalize_email(" Alice@Example.COM "),
+            Ok("Alice@example.com".to_string()),
+            "Domains should be lowercased"
+        );
+
+        for email in &["alice", "@example.com", "alice@", "alice@example", "alice@@example.com", "al ice@example.com", "alice@example..com"] {
+            assert!(normalize_email(email).is_err(), "{} should be rejected", email);
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use tokio_pg_mapper_derive::PostgresMapper;
+
+/// What a single-use user token can be redeemed for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenPurpose {
+    PasswordReset,
+    EmailVerification,
+}
+
+impl TokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenPurpose::PasswordReset => "password_reset",
+            TokenPurpose::EmailVerification => "email_verification",
+        }
+    }
+}
+
+/// Single-use, expiring token sent to a user by email.
+/// Only the digest of the token is stored.
+#[derive(Clone, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "user_tokens")]
+pub struct UserToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub purpose: String,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    /// Address an email verification token was sent to
+    pub email: Option<String>,
+}
@@ -0,0 +1,148 @@
+use deadpool_postgres::{Client, Pool};
+use slog_scope::error;
+use uuid::Uuid;
+use std::sync::Arc;
+use tokio_pg_mapper::FromTokioPostgresRow;
+
+use crate::{errors::{AppError, AppErrorType}, models::access_token::{AccessToken, Scope}};
+
+pub struct AccessTokenRepository {
+    pool: Arc<Pool>,
+}
+
+impl AccessTokenRepository {
+    pub fn new(pool: Arc<Pool>) -> AccessTokenRepository {
+        AccessTokenRepository { pool }
+    }
+
+    pub async fn create(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        scopes: &[Scope],
+        token_hash: &str,
+        ttl: Option<i64>,
+    ) -> Result<AccessToken, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing access tokens. {}", err; "query" => "create_access_token");
+            err
+        })?;
+
+        // A null ttl makes a null expiration
+        let statement = client
+        .prepare("insert into access_tokens (user_id, name, scopes, token_hash, expires_at) values ($1, $2, $3, $4, current_timestamp + make_interval(secs => $5)) returning *")
+        .await?;
+
+        let scopes: Vec<&str> = scopes.iter().map(|scope| scope.as_str()).collect();
+        let ttl: Option<f64> = ttl.map(|ttl| ttl as f64);
+
+        client
+            .query(&statement, &[&user_id, &name, &scopes, &token_hash, &ttl])
+            .await?
+            .iter()
+            .map(|row| AccessToken::from_row_ref(row))
+            .collect::<Result<Vec<AccessToken>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                message: Some("Error creating access token.".to_string()),
+                cause: None,
+                error_type: AppErrorType::DbError,
+                field: None
+            })
+    }
+
+    /// Marks an active token as used, failing if it was revoked or expired
+    pub async fn touch(&self, token_hash: &str) -> Result<AccessToken, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing access tokens. {}", err; "query" => "touch");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update access_tokens set last_used_at = current_timestamp where token_hash = $1 and revoked_at is null and (expires_at is null or expires_at > current_timestamp) returning *")
+        .await?;
+
+        client
+            .query(&statement, &[&token_hash])
+            .await?
+            .iter()
+            .map(|row| AccessToken::from_row_ref(row))
+            .collect::<Result<Vec<AccessToken>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                message: Some("Invalid, expired or revoked access token".to_string()),
+                cause: None,
+                error_type: AppErrorType::Unauthorized,
+                field: None
+            })
+    }
+
+    pub async fn for_user(&self, user_id: Uuid) -> Result<Vec<AccessToken>, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing access tokens. {}", err; "query" => "access_tokens_for_user");
+            err
+        })?;
+
+        let statement = client
+        .prepare("select * from access_tokens where user_id = $1 and revoked_at is null order by created_at desc")
+        .await?;
+
+        let tokens = client
+            .query(&statement, &[&user_id])
+            .await?
+            .iter()
+            .map(|row| AccessToken::from_row_ref(row))
+            .collect::<Result<Vec<AccessToken>, _>>()
+            .map_err(|err| {
+                error!("Error getting parsing access tokens. {}", err; "query" => "access_tokens_for_user");
+                err
+            })?;
+
+        Ok(tokens)
+    }
+
+    pub async fn revoke(&self, id: Uuid, user_id: Uuid) -> Result<bool, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing access tokens. {}", err; "query" => "revoke");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update access_tokens set revoked_at = current_timestamp where id = $1 and user_id = $2 and revoked_at is null")
+        .await?;
+
+        let revoked = client.execute(&statement, &[&id, &user_id]).await?;
+
+        Ok(revoked > 0)
+    }
+
+    pub async fn revoke_all(&self, user_id: Uuid) -> Result<u64, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing access tokens. {}", err; "query" => "revoke_all");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update access_tokens set revoked_at = current_timestamp where user_id = $1 and revoked_at is null")
+        .await?;
+
+        let revoked = client.execute(&statement, &[&user_id]).await?;
+
+        Ok(revoked)
+    }
+}
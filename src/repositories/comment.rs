@@ -0,0 +1,273 @@
+use async_trait::async_trait;
+use dataloader::{cached::Loader, BatchFn};
+use deadpool_postgres::{Client, Pool};
+use slog_scope::{error, info};
+use std::{collections::HashMap, sync::Arc};
+use tokio_pg_mapper::FromTokioPostgresRow;
+use uuid::Uuid;
+
+use crate::{
+    errors::{AppError, AppErrorType},
+    models::comment::{Comment, CreateComment, UpdateComment},
+};
+
+pub struct CommentRepository {
+    pool: Arc<Pool>,
+}
+
+/// What a loader groups comments by
+#[derive(Clone, Copy)]
+pub enum CommentParent {
+    /// Top level comments of each post
+    Post,
+    /// Direct replies to each comment
+    Comment,
+}
+
+pub struct CommentBatcher {
+    pool: Arc<Pool>,
+    parent: CommentParent,
+}
+
+pub type CommentLoader = Loader<Uuid, Vec<Comment>, AppError, CommentBatcher>;
+
+pub fn get_comments_loader(pool: Arc<Pool>) -> CommentLoader {
+    Loader::new(CommentBatcher { pool, parent: CommentParent::Post }).with_yield_count(100)
+}
+
+pub fn get_replies_loader(pool: Arc<Pool>) -> CommentLoader {
+    Loader::new(CommentBatcher { pool, parent: CommentParent::Comment }).with_yield_count(100)
+}
+
+impl CommentBatcher {
+    pub async fn get_comments_by_parents_ids(
+        &self,
+        hashmap: &mut HashMap<Uuid, Vec<Comment>>,
+        ids: Vec<Uuid>,
+    ) -> Result<(), AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing comments. {}", err; "query" => "get_comments_by_parents_ids");
+            err
+        })?;
+
+        let query = match self.parent {
+            CommentParent::Post => "select comments.post_id as key, comments.*, (select count(*) from comments replies where replies.parent_id = comments.id and replies.author_id in (select id from users where deleted_at is null)) as reply_count from comments where comments.post_id = ANY($1) and comments.parent_id is null and comments.author_id in (select id from users where deleted_at is null) order by comments.created_at",
+            CommentParent::Comment => "select comments.parent_id as key, comments.*, (select count(*) from comments replies where replies.parent_id = comments.id and replies.author_id in (select id from users where deleted_at is null)) as reply_count from comments where comments.parent_id = ANY($1) and comments.author_id in (select id from users where deleted_at is null) order by comments.created_at",
+        };
+
+        let statement = client.prepare(query).await?;
+
+        for row in client.query(&statement, &[&ids]).await? {
+            let key: Uuid = row.try_get("key")?;
+            let comment = Comment::from_row_ref(&row).map_err(|err| {
+                error!("Error getting parsing comments. {}", err; "query" => "get_comments_by_parents_ids");
+                err
+            })?;
+
+            hashmap.entry(key).or_insert_with(|| Vec::<Comment>::new()).push(comment);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BatchFn<Uuid, Vec<Comment>> for CommentBatcher {
+    type Error = AppError;
+
+    async fn load(&self, keys: &[Uuid]) -> HashMap<Uuid, Result<Vec<Comment>, AppError>> {
+        info!("Loading comments batch {:?}", keys);
+
+        let mut comments_map: HashMap<Uuid, Vec<Comment>> = HashMap::new();
+
+        let result: Result<(), AppError> = self
+            .get_comments_by_parents_ids(&mut comments_map, keys.into())
+            .await;
+
+        keys.iter()
+            .map(move |id| {
+                let entry = comments_map.entry(*id).or_insert_with(|| vec![]);
+                (id.clone(), result.clone().map(|_| entry.clone()))
+            })
+            .collect::<HashMap<_, _>>()
+    }
+}
+
+/// Loads single comments by id, for replies pointing back at their parent
+pub struct CommentByIdBatcher {
+    pool: Arc<Pool>,
+}
+
+pub type CommentByIdLoader = Loader<Uuid, Comment, AppError, CommentByIdBatcher>;
+
+pub fn get_comment_by_id_loader(pool: Arc<Pool>) -> CommentByIdLoader {
+    Loader::new(CommentByIdBatcher { pool }).with_yield_count(100)
+}
+
+impl CommentByIdBatcher {
+    pub async fn get_comments_by_ids(&self, hashmap: &mut HashMap<Uuid, Comment>, ids: Vec<Uuid>) -> Result<(), AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing comments. {}", err; "query" => "get_comments_by_ids");
+            err
+        })?;
+
+        let statement = client
+            .prepare("select comments.*, (select count(*) from comments replies where replies.parent_id = comments.id and replies.author_id in (select id from users where deleted_at is null)) as reply_count from comments where comments.id = ANY($1) and comments.author_id in (select id from users where deleted_at is null)")
+            .await?;
+
+        for row in client.query(&statement, &[&ids]).await? {
+            let comment = Comment::from_row_ref(&row).map_err(|err| {
+                error!("Error getting parsing comments. {}", err; "query" => "get_comments_by_ids");
+                err
+            })?;
+
+            hashmap.insert(comment.id, comment);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BatchFn<Uuid, Comment> for CommentByIdBatcher {
+    type Error = AppError;
+
+    async fn load(&self, keys: &[Uuid]) -> HashMap<Uuid, Result<Comment, AppError>> {
+        info!("Loading comments by id batch {:?}", keys);
+
+        let mut comments_map: HashMap<Uuid, Comment> = HashMap::new();
+
+        let result: Result<(), AppError> = self
+            .get_comments_by_ids(&mut comments_map, keys.into())
+            .await;
+
+        keys.iter()
+            .map(move |id| {
+                let comment = result.clone().and_then(|_| {
+                    comments_map.remove(id).ok_or(AppError {
+                        cause: None,
+                        message: Some(format!("Comment with id {} not found", id)),
+                        error_type: AppErrorType::NotFoundError,
+                        field: None
+                    })
+                });
+                (id.clone(), comment)
+            })
+            .collect::<HashMap<_, _>>()
+    }
+}
+
+impl CommentRepository {
+    pub fn new(pool: Arc<Pool>) -> CommentRepository {
+        CommentRepository { pool }
+    }
+
+    pub async fn get(&self, id: Uuid) -> Result<Comment, AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing comments. {}", err; "query" => "get");
+            err
+        })?;
+
+        let statement = client
+            .prepare("select comments.*, (select count(*) from comments replies where replies.parent_id = comments.id and replies.author_id in (select id from users where deleted_at is null)) as reply_count from comments where comments.id = $1 and comments.author_id in (select id from users where deleted_at is null)")
+            .await?;
+
+        client
+            .query(&statement, &[&id])
+            .await?
+            .iter()
+            .map(|row| Comment::from_row_ref(row))
+            .collect::<Result<Vec<Comment>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                cause: None,
+                message: Some(format!("Comment with id {} not found", id)),
+                error_type: AppErrorType::NotFoundError,
+                field: None,
+            })
+    }
+
+    /// Comments on a post whose author is still around, replying to the parent if given
+    pub async fn create(&self, author_id: Uuid, input: CreateComment, parent: Option<&Comment>) -> Result<Comment, AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing comments. {}", err; "query" => "create comment");
+            err
+        })?;
+
+        let statement = client
+            .prepare("with inserted as (insert into comments (author_id, post_id, body, parent_id, depth) select $1, id, $3, $4, $5 from posts where id = $2 and author_id in (select id from users where deleted_at is null) returning *) select inserted.*, 0::bigint as reply_count from inserted")
+            .await?;
+
+        let parent_id = parent.map(|parent| parent.id);
+        let depth = parent.map_or(0, |parent| parent.depth + 1);
+
+        client
+            .query(&statement, &[&author_id, &input.post_id, &input.body, &parent_id, &depth])
+            .await?
+            .iter()
+            .map(|row| Comment::from_row_ref(row))
+            .collect::<Result<Vec<Comment>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                cause: None,
+                message: Some(format!("Post with id {} not found", input.post_id)),
+                error_type: AppErrorType::NotFoundError,
+                field: None,
+            })
+    }
+
+    pub async fn update(&self, id: Uuid, input: UpdateComment) -> Result<Comment, AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing comments. {}", err; "query" => "update comment");
+            err
+        })?;
+
+        let statement = client
+            .prepare("update comments set body = $2, updated_at = current_timestamp where id = $1 returning *, (select count(*) from comments replies where replies.parent_id = comments.id and replies.author_id in (select id from users where deleted_at is null)) as reply_count")
+            .await?;
+
+        client
+            .query(&statement, &[&id, &input.body])
+            .await?
+            .iter()
+            .map(|row| Comment::from_row_ref(row))
+            .collect::<Result<Vec<Comment>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                cause: None,
+                message: Some(format!("Comment with id {} not found", id)),
+                error_type: AppErrorType::NotFoundError,
+                field: None,
+            })
+    }
+
+    /// Deletes the comment, its direct replies become top level comments
+    /// and everything below them moves up with them
+    pub async fn delete(&self, id: Uuid) -> Result<bool, AppError> {
+        let mut client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing comments. {}", err; "query" => "delete comment");
+            err
+        })?;
+
+        let transaction = client.transaction().await?;
+
+        // Renumber while the replies still point at the comment, deleting it unlinks them
+        let promote = transaction
+            .prepare("with recursive thread (id, depth) as (\
+                select id, 0 from comments where parent_id = $1 \
+                union all \
+                select comments.id, thread.depth + 1 from comments join thread on comments.parent_id = thread.id) \
+                update comments set depth = thread.depth from thread where comments.id = thread.id")
+            .await?;
+
+        transaction.execute(&promote, &[&id]).await?;
+
+        let statement = transaction.prepare("delete from comments where id = $1").await?;
+
+        let deleted = transaction.execute(&statement, &[&id]).await?;
+
+        transaction.commit().await?;
+
+        Ok(deleted > 0)
+    }
+}
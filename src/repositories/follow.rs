@@ -0,0 +1,190 @@
+use async_trait::async_trait;
+use dataloader::{cached::Loader, BatchFn};
+use deadpool_postgres::{Client, Pool};
+use slog_scope::{error, info};
+use std::{collections::HashMap, sync::Arc};
+use tokio_pg_mapper::FromTokioPostgresRow;
+use tokio_postgres::Error;
+use uuid::Uuid;
+
+use crate::{
+    errors::{AppError, AppErrorType},
+    models::user::User,
+};
+
+pub struct FollowRepository {
+    pool: Arc<Pool>,
+}
+
+/// Which side of the follow graph a loader returns for each user
+#[derive(Clone, Copy)]
+pub enum FollowDirection {
+    Followers,
+    Following,
+}
+
+pub struct FollowBatcher {
+    pool: Arc<Pool>,
+    direction: FollowDirection,
+}
+
+pub type FollowLoader = Loader<Uuid, Vec<User>, AppError, FollowBatcher>;
+
+pub fn get_followers_loader(pool: Arc<Pool>) -> FollowLoader {
+    Loader::new(FollowBatcher { pool, direction: FollowDirection::Followers }).with_yield_count(100)
+}
+
+pub fn get_following_loader(pool: Arc<Pool>) -> FollowLoader {
+    Loader::new(FollowBatcher { pool, direction: FollowDirection::Following }).with_yield_count(100)
+}
+
+impl FollowBatcher {
+    pub async fn get_users_by_follows(
+        &self,
+        hashmap: &mut HashMap<Uuid, Vec<User>>,
+        ids: Vec<Uuid>,
+    ) -> Result<(), AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing follows. {}", err; "query" => "get_users_by_follows");
+            err
+        })?;
+
+        let query = match self.direction {
+            FollowDirection::Followers => "select follows.followee_id as key, users.* from follows join users on users.id = follows.follower_id where follows.followee_id = ANY($1) and users.deleted_at is null order by follows.created_at desc",
+            FollowDirection::Following => "select follows.follower_id as key, users.* from follows join users on users.id = follows.followee_id where follows.follower_id = ANY($1) and users.deleted_at is null order by follows.created_at desc",
+        };
+
+        let statement = client.prepare(query).await?;
+
+        for row in client.query(&statement, &[&ids]).await? {
+            let key: Uuid = row.try_get("key")?;
+            let user = User::from_row_ref(&row).map_err(|err| {
+                error!("Error getting parsing follows. {}", err; "query" => "get_users_by_follows");
+                err
+            })?;
+
+            hashmap.entry(key).or_insert_with(|| Vec::<User>::new()).push(user);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BatchFn<Uuid, Vec<User>> for FollowBatcher {
+    type Error = AppError;
+
+    async fn load(&self, keys: &[Uuid]) -> HashMap<Uuid, Result<Vec<User>, AppError>> {
+        info!("Loading follows batch {:?}", keys);
+
+        let mut users_map: HashMap<Uuid, Vec<User>> = HashMap::new();
+
+        let result: Result<(), AppError> = self
+            .get_users_by_follows(&mut users_map, keys.into())
+            .await;
+
+        keys.iter()
+            .map(move |id| {
+                let entry = users_map.entry(*id).or_insert_with(|| vec![]);
+                (id.clone(), result.clone().map(|_| entry.clone()))
+            })
+            .collect::<HashMap<_, _>>()
+    }
+}
+
+/// Counts followers per user without loading them
+pub struct FollowerCountBatcher {
+    pool: Arc<Pool>,
+}
+
+pub type FollowerCountLoader = Loader<Uuid, i64, AppError, FollowerCountBatcher>;
+
+pub fn get_follower_count_loader(pool: Arc<Pool>) -> FollowerCountLoader {
+    Loader::new(FollowerCountBatcher { pool }).with_yield_count(100)
+}
+
+impl FollowerCountBatcher {
+    pub async fn get_follower_counts(
+        &self,
+        hashmap: &mut HashMap<Uuid, i64>,
+        ids: Vec<Uuid>,
+    ) -> Result<(), AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing follows. {}", err; "query" => "get_follower_counts");
+            err
+        })?;
+
+        let statement = client
+            .prepare("select follows.followee_id as key, count(*) as count from follows join users on users.id = follows.follower_id where follows.followee_id = ANY($1) and users.deleted_at is null group by follows.followee_id")
+            .await?;
+
+        for row in client.query(&statement, &[&ids]).await? {
+            hashmap.insert(row.try_get("key")?, row.try_get("count")?);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BatchFn<Uuid, i64> for FollowerCountBatcher {
+    type Error = AppError;
+
+    async fn load(&self, keys: &[Uuid]) -> HashMap<Uuid, Result<i64, AppError>> {
+        info!("Loading follower counts batch {:?}", keys);
+
+        let mut counts_map: HashMap<Uuid, i64> = HashMap::new();
+
+        let result: Result<(), AppError> = self
+            .get_follower_counts(&mut counts_map, keys.into())
+            .await;
+
+        keys.iter()
+            .map(move |id| {
+                let count = counts_map.get(id).cloned().unwrap_or(0);
+                (id.clone(), result.clone().map(|_| count))
+            })
+            .collect::<HashMap<_, _>>()
+    }
+}
+
+impl FollowRepository {
+    pub fn new(pool: Arc<Pool>) -> FollowRepository {
+        FollowRepository { pool }
+    }
+
+    pub async fn follow(&self, follower_id: Uuid, followee_id: Uuid) -> Result<bool, AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing follows. {}", err; "query" => "follow");
+            err
+        })?;
+
+        let statement = client
+            .prepare("insert into follows (follower_id, followee_id) select $1, id from users where id = $2 and deleted_at is null on conflict do nothing")
+            .await?;
+
+        let inserted = client
+            .execute(&statement, &[&follower_id, &followee_id])
+            .await
+            .map_err(|err: Error| {
+                AppError::from(err).with_message_for(AppErrorType::InvalidField, "You can't follow yourself".to_string())
+            })?;
+
+        Ok(inserted > 0)
+    }
+
+    pub async fn unfollow(&self, follower_id: Uuid, followee_id: Uuid) -> Result<bool, AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing follows. {}", err; "query" => "unfollow");
+            err
+        })?;
+
+        let statement = client
+            .prepare("delete from follows where follower_id = $1 and followee_id = $2")
+            .await?;
+
+        let deleted = client.execute(&statement, &[&follower_id, &followee_id]).await?;
+
+        Ok(deleted > 0)
+    }
+}
@@ -0,0 +1,122 @@
+use deadpool_postgres::{Client, Pool};
+use slog_scope::error;
+use std::sync::Arc;
+use tokio_pg_mapper::FromTokioPostgresRow;
+
+use crate::{
+    errors::{AppError, AppErrorType},
+    models::login_attempt::{LoginAttempt, ThrottleKey, LOCKOUT_BASE_SECS, LOCKOUT_MAX_SECS},
+};
+
+pub struct LoginAttemptRepository {
+    pool: Arc<Pool>,
+}
+
+impl LoginAttemptRepository {
+    pub fn new(pool: Arc<Pool>) -> LoginAttemptRepository {
+        LoginAttemptRepository { pool }
+    }
+
+    /// Counts an attempt against every key before the password is checked, so concurrent
+    /// attempts can't all slip in under the limit. Fails with RateLimited if any key is locked out.
+    /// Each attempt past the limit doubles the lockout, counts restart after a quiet day.
+    pub async fn record_attempt(&self, keys: &[ThrottleKey]) -> Result<Vec<LoginAttempt>, AppError> {
+        let mut client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing login attempts. {}", err; "query" => "record_attempt");
+            err
+        })?;
+
+        let transaction = client.transaction().await?;
+
+        // Locked rows are left alone and don't come back
+        let count = transaction
+        .prepare("insert into login_attempts (key, failures) values ($1, 1) on conflict (key) do update set failures = case when login_attempts.last_failed_at < current_timestamp - interval '1 day' then 1 else login_attempts.failures + 1 end, last_failed_at = current_timestamp where login_attempts.locked_until is null or login_attempts.locked_until <= current_timestamp returning key")
+        .await?;
+
+        let lock = transaction
+        .prepare("update login_attempts set locked_until = case when failures >= $2 then current_timestamp + make_interval(secs => least($3 * power(2, failures - $2), $4)) end where key = $1 returning *")
+        .await?;
+
+        // Always take the row locks in the same order so concurrent attempts queue up instead of deadlocking
+        let mut keys = keys.to_vec();
+        keys.sort_by_key(|key| key.key());
+
+        let mut attempts = Vec::with_capacity(keys.len());
+
+        for key in &keys {
+            if transaction.query(&count, &[&key.key()]).await?.is_empty() {
+                return Err(AppError {
+                    message: Some("Too many failed logins, try again later".to_string()),
+                    cause: None,
+                    error_type: AppErrorType::RateLimited,
+                    field: None
+                });
+            }
+
+            let attempt = transaction
+                .query(&lock, &[&key.key(), &key.max_failures(), &LOCKOUT_BASE_SECS, &LOCKOUT_MAX_SECS])
+                .await?
+                .iter()
+                .map(|row| LoginAttempt::from_row_ref(row))
+                .collect::<Result<Vec<LoginAttempt>, _>>()?
+                .pop()
+                .ok_or(AppError {
+                    message: Some("Error recording login attempt.".to_string()),
+                    cause: None,
+                    error_type: AppErrorType::DbError,
+                    field: None
+                })?;
+
+            attempts.push(attempt);
+        }
+
+        transaction.commit().await?;
+
+        Ok(attempts)
+    }
+
+    /// Takes back an attempt that didn't turn out to be a wrong password
+    pub async fn release(&self, keys: &[ThrottleKey]) -> Result<(), AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing login attempts. {}", err; "query" => "release");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update login_attempts set failures = greatest(failures - 1, 0), locked_until = case when failures - 1 >= $2 then locked_until end where key = $1")
+        .await?;
+
+        for key in keys {
+            client.execute(&statement, &[&key.key(), &key.max_failures()]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the failures counted against the keys, lifting any lockout
+    pub async fn clear(&self, keys: &[ThrottleKey]) -> Result<bool, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing login attempts. {}", err; "query" => "clear");
+            err
+        })?;
+
+        let statement = client
+        .prepare("delete from login_attempts where key = ANY($1)")
+        .await?;
+
+        let keys: Vec<String> = keys.iter().map(|key| key.key()).collect();
+
+        let cleared = client.execute(&statement, &[&keys]).await?;
+
+        Ok(cleared > 0)
+    }
+}
@@ -1,2 +1,8 @@
 pub mod user;
-pub mod post;
\ No newline at end of file
+pub mod post;
+pub mod session;
+pub mod user_token;
+pub mod follow;
+pub mod access_token;
+pub mod login_attempt;
+pub mod comment;
\ No newline at end of file
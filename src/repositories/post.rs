@@ -4,12 +4,11 @@ use deadpool_postgres::{Client, Pool};
 use slog_scope::{error, info};
 use std::{collections::HashMap, sync::Arc};
 use tokio_pg_mapper::FromTokioPostgresRow;
-use tokio_postgres::{error::SqlState, Error};
 use uuid::Uuid;
 
 use crate::{
-    errors::{AppError, AppErrorType},
-    models::post::{CreatePost, Post},
+    errors::{violated_constraint, AppError, AppErrorType},
+    models::post::{slug_candidate, slugify, CreatePost, Post, UpdatePost, SLUG_ATTEMPTS},
 };
 
 pub struct PostRepository {
@@ -38,7 +37,7 @@ impl PostBatcher {
         })?;
 
         let statement = client
-            .prepare("select * from posts where author_id = ANY($1)")
+            .prepare("select * from posts where author_id = ANY($1) and author_id in (select id from users where deleted_at is null)")
             .await?;
 
         client
@@ -86,6 +85,70 @@ impl BatchFn<Uuid, Vec<Post>> for PostBatcher {
     }
 }
 
+/// Loads single posts by id, for comments pointing back at their post
+pub struct PostByIdBatcher {
+    pool: Arc<Pool>,
+}
+
+pub type PostByIdLoader = Loader<Uuid, Post, AppError, PostByIdBatcher>;
+
+pub fn get_post_by_id_loader(pool: Arc<Pool>) -> PostByIdLoader {
+    Loader::new(PostByIdBatcher { pool }).with_yield_count(100)
+}
+
+impl PostByIdBatcher {
+    pub async fn get_posts_by_ids(&self, hashmap: &mut HashMap<Uuid, Post>, ids: Vec<Uuid>) -> Result<(), AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing posts. {}", err; "query" => "get_posts_by_ids");
+            err
+        })?;
+
+        let statement = client
+            .prepare("select * from posts where id = ANY($1) and author_id in (select id from users where deleted_at is null)")
+            .await?;
+
+        for row in client.query(&statement, &[&ids]).await? {
+            let post = Post::from_row_ref(&row).map_err(|err| {
+                error!("Error getting parsing posts. {}", err; "query" => "get_posts_by_ids");
+                err
+            })?;
+
+            hashmap.insert(post.id, post);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BatchFn<Uuid, Post> for PostByIdBatcher {
+    type Error = AppError;
+
+    async fn load(&self, keys: &[Uuid]) -> HashMap<Uuid, Result<Post, AppError>> {
+        info!("Loading posts by id batch {:?}", keys);
+
+        let mut posts_map: HashMap<Uuid, Post> = HashMap::new();
+
+        let result: Result<(), AppError> = self
+            .get_posts_by_ids(&mut posts_map, keys.into())
+            .await;
+
+        keys.iter()
+            .map(move |id| {
+                let post = result.clone().and_then(|_| {
+                    posts_map.remove(id).ok_or(AppError {
+                        cause: None,
+                        message: Some(format!("Post with id {} not found", id)),
+                        error_type: AppErrorType::NotFoundError,
+                        field: None
+                    })
+                });
+                (id.clone(), post)
+            })
+            .collect::<HashMap<_, _>>()
+    }
+}
+
 impl PostRepository {
     pub fn new(pool: Arc<Pool>) -> PostRepository {
         PostRepository { pool }
@@ -93,11 +156,11 @@ impl PostRepository {
 
     pub async fn get(&self, id: Uuid) -> Result<Post, AppError> {
         let client: Client = self.pool.get().await.map_err(|err| {
-            error!("Error getting parsing users. {}", err; "query" => "get");
+            error!("Error getting parsing posts. {}", err; "query" => "get");
             err
         })?;
 
-        let statement = client.prepare("select * from post where id = $1").await?;
+        let statement = client.prepare("select * from posts where id = $1 and author_id in (select id from users where deleted_at is null)").await?;
 
         client
             .query(&statement, &[&id])
@@ -110,6 +173,36 @@ impl PostRepository {
                 cause: None,
                 message: Some(format!("Post with id {} not found", id)),
                 error_type: AppErrorType::NotFoundError,
+                field: None,
+            })
+    }
+
+    /// The post currently using the slug, or else the one that used it before.
+    /// The returned post carries its canonical slug.
+    pub async fn get_by_slug(&self, slug: &str) -> Result<Post, AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing posts. {}", err; "query" => "get_by_slug");
+            err
+        })?;
+
+        let statement = client
+            .prepare("select posts.* from posts left join post_slug_history history on history.post_id = posts.id and history.slug = $1 \
+                where (posts.slug = $1 or history.slug is not null) and posts.author_id in (select id from users where deleted_at is null) \
+                order by posts.slug = $1 desc limit 1")
+            .await?;
+
+        client
+            .query(&statement, &[&slug])
+            .await?
+            .iter()
+            .map(|row| Post::from_row_ref(row))
+            .collect::<Result<Vec<Post>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                cause: None,
+                message: Some(format!("Post with slug {} not found", slug)),
+                error_type: AppErrorType::NotFoundError,
+                field: None,
             })
     }
 
@@ -119,7 +212,7 @@ impl PostRepository {
             err
         })?;
 
-        let statement = client.prepare("select * from posts").await?;
+        let statement = client.prepare("select * from posts where author_id in (select id from users where deleted_at is null)").await?;
 
         let posts = client
             .query(&statement, &[])
@@ -135,7 +228,10 @@ impl PostRepository {
         Ok(posts)
     }
 
-    pub async fn create(&self, input: CreatePost) -> Result<Post, AppError> {
+    /// Stores the post under its own slug, or one generated from the title.
+    /// Generated slugs that are taken, now or as another post's old slug, get a suffix,
+    /// a taken explicit slug is a Conflict.
+    pub async fn create(&self, author_id: Uuid, input: CreatePost) -> Result<Post, AppError> {
         let client: Client = self.pool.get().await.map_err(|err| {
             error!("Error getting parsing posts. {}", err; "query" => "create post");
             err
@@ -145,51 +241,121 @@ impl PostRepository {
         .prepare("insert into posts (author_id, slug, title, description, body) values ($1, $2, $3, $4, $5) returning *")
         .await?;
 
-        let slug = match input.slug {
-            Some(s) => s,
-            None => Uuid::new_v4().to_string(),
+        let (base, attempts) = match input.slug {
+            Some(slug) => (slug, 1),
+            None => (slugify(&input.title), SLUG_ATTEMPTS),
         };
 
-        let author_id = input.author_id.clone();
-
-        let post = client
-            .query(
-                &statement,
-                &[
-                    &input.author_id,
-                    &slug,
-                    &input.title,
-                    &input.description,
-                    &input.body,
-                ],
-            )
+        let mut attempt = 0;
+
+        loop {
+            let slug = slug_candidate(&base, attempt);
+            attempt += 1;
+
+            let rows = client
+                .query(
+                    &statement,
+                    &[
+                        &author_id,
+                        &slug,
+                        &input.title,
+                        &input.description,
+                        &input.body,
+                    ],
+                )
+                .await;
+
+            let rows = match rows {
+                Ok(rows) => rows,
+                Err(err) => {
+                    let unknown_author = violated_constraint(&err) == Some("posts_author_id_fkey");
+
+                    match AppError::from(err) {
+                        err if err.error_type == AppErrorType::Conflict && attempt < attempts => continue,
+                        err if unknown_author => {
+                            return Err(AppError {
+                                message: Some(format!("Author with id {} does not exist", author_id)),
+                                ..err
+                            })
+                        }
+                        err => {
+                            return Err(err
+                                .with_message_for(AppErrorType::Conflict, format!("Slug {} already exists", slug))
+                                .with_field_for(AppErrorType::Conflict, "slug"))
+                        }
+                    }
+                }
+            };
+
+            return rows
+                .iter()
+                .map(|row| Post::from_row_ref(row))
+                .collect::<Result<Vec<Post>, _>>()?
+                .pop()
+                .ok_or(AppError {
+                    message: Some("Error creating Post.".to_string()),
+                    cause: None,
+                    error_type: AppErrorType::DbError,
+                    field: None,
+                });
+        }
+    }
+
+    /// Applies the supplied fields, a new slug keeps the old one in the history
+    pub async fn update(&self, id: Uuid, input: UpdatePost) -> Result<Post, AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing posts. {}", err; "query" => "update post");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update posts set \
+            slug = coalesce($2, slug), \
+            title = coalesce($3, title), \
+            description = coalesce($4, description), \
+            body = coalesce($5, body), \
+            updated_at = current_timestamp \
+            where id = $1 returning *")
+        .await?;
+
+        client
+            .query(&statement, &[
+                &id,
+                &input.slug,
+                &input.title,
+                &input.description,
+                &input.body,
+            ])
             .await
-            .map_err(|err: Error| match err.code() {
-                Some(code) => match code {
-                    c if c == &SqlState::UNIQUE_VIOLATION => AppError {
-                        cause: Some(err.to_string()),
-                        message: Some(format!("Slug {} already exists", slug)),
-                        error_type: AppErrorType::InvalidField,
-                    },
-                    c if c == &SqlState::FOREIGN_KEY_VIOLATION => AppError {
-                        cause: Some(err.to_string()),
-                        message: Some(format!("Author with id {} does not exists", author_id)),
-                        error_type: AppErrorType::InvalidField,
-                    },
-                    _ => AppError::from(err),
-                },
-                _ => AppError::from(err),
+            .map_err(|err| match &input.slug {
+                // Taken by another post, now or as one of its old slugs
+                Some(slug) => AppError::from(err)
+                    .with_message_for(AppErrorType::Conflict, format!("Slug {} already exists", slug))
+                    .with_field_for(AppErrorType::Conflict, "slug"),
+                None => AppError::from(err),
             })?
             .iter()
             .map(|row| Post::from_row_ref(row))
             .collect::<Result<Vec<Post>, _>>()?
             .pop()
             .ok_or(AppError {
-                message: Some("Error creating Post.".to_string()),
                 cause: None,
-                error_type: AppErrorType::DbError,
-            })?;
+                message: Some(format!("Post with id {} not found", id)),
+                error_type: AppErrorType::NotFoundError,
+                field: None,
+            })
+    }
+
+    pub async fn delete(&self, id: Uuid) -> Result<bool, AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing posts. {}", err; "query" => "delete post");
+            err
+        })?;
+
+        let statement = client.prepare("delete from posts where id = $1").await?;
+
+        let deleted = client.execute(&statement, &[&id]).await?;
 
-        Ok(post)
+        Ok(deleted > 0)
     }
 }
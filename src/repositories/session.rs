@@ -0,0 +1,211 @@
+use deadpool_postgres::{Client, Pool};
+use slog_scope::error;
+use uuid::Uuid;
+use std::sync::Arc;
+use tokio_pg_mapper::FromTokioPostgresRow;
+
+use crate::{errors::{AppError, AppErrorType}, models::session::Session};
+
+pub struct SessionRepository {
+    pool: Arc<Pool>,
+}
+
+impl SessionRepository {
+    pub fn new(pool: Arc<Pool>) -> SessionRepository {
+        SessionRepository { pool }
+    }
+
+    pub async fn create(
+        &self,
+        user_id: Uuid,
+        refresh_token_hash: &str,
+        user_agent: Option<String>,
+        ip: Option<String>,
+        ttl: i64,
+    ) -> Result<Session, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing sessions. {}", err; "query" => "create_session");
+            err
+        })?;
+
+        let statement = client
+        .prepare("insert into sessions (user_id, refresh_token_hash, user_agent, ip, expires_at) values ($1, $2, $3, $4, current_timestamp + make_interval(secs => $5)) returning *")
+        .await?;
+
+        client
+            .query(&statement, &[
+                &user_id,
+                &refresh_token_hash,
+                &user_agent,
+                &ip,
+                &(ttl as f64),
+            ])
+            .await?
+            .iter()
+            .map(|row| Session::from_row_ref(row))
+            .collect::<Result<Vec<Session>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                message: Some("Error creating Session.".to_string()),
+                cause: None,
+                error_type: AppErrorType::DbError,
+                field: None
+            })
+    }
+
+    /// Marks an active session as seen, failing if it was revoked or expired
+    pub async fn touch(&self, id: Uuid) -> Result<Session, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing sessions. {}", err; "query" => "touch");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update sessions set last_seen_at = current_timestamp where id = $1 and revoked_at is null and expires_at > current_timestamp returning *")
+        .await?;
+
+        client
+            .query(&statement, &[&id])
+            .await?
+            .iter()
+            .map(|row| Session::from_row_ref(row))
+            .collect::<Result<Vec<Session>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                message: Some("Session expired or revoked".to_string()),
+                cause: None,
+                error_type: AppErrorType::Unauthorized,
+                field: None
+            })
+    }
+
+    /// Swaps the refresh token of an active session for a new one.
+    /// A refresh token can only be used once.
+    pub async fn rotate(
+        &self,
+        refresh_token_hash: &str,
+        new_refresh_token_hash: &str,
+        user_agent: Option<String>,
+        ip: Option<String>,
+        ttl: i64,
+    ) -> Result<Session, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing sessions. {}", err; "query" => "rotate");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update sessions set refresh_token_hash = $2, user_agent = coalesce($3, user_agent), ip = coalesce($4, ip), last_seen_at = current_timestamp, expires_at = current_timestamp + make_interval(secs => $5) where refresh_token_hash = $1 and revoked_at is null and expires_at > current_timestamp returning *")
+        .await?;
+
+        client
+            .query(&statement, &[
+                &refresh_token_hash,
+                &new_refresh_token_hash,
+                &user_agent,
+                &ip,
+                &(ttl as f64),
+            ])
+            .await?
+            .iter()
+            .map(|row| Session::from_row_ref(row))
+            .collect::<Result<Vec<Session>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                message: Some("Invalid or expired refresh token".to_string()),
+                cause: None,
+                error_type: AppErrorType::Unauthorized,
+                field: None
+            })
+    }
+
+    pub async fn for_user(&self, user_id: Uuid) -> Result<Vec<Session>, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing sessions. {}", err; "query" => "sessions_for_user");
+            err
+        })?;
+
+        let statement = client
+        .prepare("select * from sessions where user_id = $1 and revoked_at is null and expires_at > current_timestamp order by last_seen_at desc")
+        .await?;
+
+        let sessions = client
+            .query(&statement, &[&user_id])
+            .await?
+            .iter()
+            .map(|row| Session::from_row_ref(row))
+            .collect::<Result<Vec<Session>, _>>()
+            .map_err(|err| {
+                error!("Error getting parsing sessions. {}", err; "query" => "sessions_for_user");
+                err
+            })?;
+
+        Ok(sessions)
+    }
+
+    pub async fn revoke(&self, id: Uuid, user_id: Uuid) -> Result<bool, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing sessions. {}", err; "query" => "revoke");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update sessions set revoked_at = current_timestamp where id = $1 and user_id = $2 and revoked_at is null")
+        .await?;
+
+        let revoked = client.execute(&statement, &[&id, &user_id]).await?;
+
+        Ok(revoked > 0)
+    }
+
+    pub async fn revoke_all(&self, user_id: Uuid) -> Result<u64, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing sessions. {}", err; "query" => "revoke_all");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update sessions set revoked_at = current_timestamp where user_id = $1 and revoked_at is null")
+        .await?;
+
+        let revoked = client.execute(&statement, &[&user_id]).await?;
+
+        Ok(revoked)
+    }
+
+    pub async fn revoke_others(&self, user_id: Uuid, session_id: Uuid) -> Result<u64, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing sessions. {}", err; "query" => "revoke_others");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update sessions set revoked_at = current_timestamp where user_id = $1 and id <> $2 and revoked_at is null")
+        .await?;
+
+        let revoked = client.execute(&statement, &[&user_id, &session_id]).await?;
+
+        Ok(revoked)
+    }
+}
@@ -1,16 +1,114 @@
+use async_trait::async_trait;
+use dataloader::{cached::Loader, BatchFn};
 use deadpool_postgres::{Client, Pool};
-use slog_scope::error;
-use tokio_postgres::{Error, error::SqlState};
+use slog_scope::{error, info};
+use tokio_postgres::Error;
 use uuid::Uuid;
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 use tokio_pg_mapper::FromTokioPostgresRow;
 
-use crate::{config::HashingService, errors::{AppError, AppErrorType}, models::user::{CreateUser, User}};
+use crate::{config::{HashingService, PasswordHash}, errors::{violated_constraint, AppError, AppErrorType}, models::{user::{normalize_email, normalize_login, normalize_username, CreateUser, Role, UpdateUser, User}, user_token::TokenPurpose}};
 
 pub struct UserRepository {
     pool: Arc<Pool>,
 }
 
+pub struct UserBatcher {
+    pool: Arc<Pool>,
+}
+
+pub type UserLoader = Loader<Uuid, User, AppError, UserBatcher>;
+
+pub fn get_users_loader(pool: Arc<Pool>) -> UserLoader {
+    Loader::new(UserBatcher { pool }).with_yield_count(100)
+}
+
+impl UserBatcher {
+    pub async fn get_users_by_ids(&self, hashmap: &mut HashMap<Uuid, User>, ids: Vec<Uuid>) -> Result<(), AppError> {
+        let client: Client = self.pool.get().await.map_err(|err| {
+            error!("Error getting parsing users. {}", err; "query" => "get_users_by_ids");
+            err
+        })?;
+
+        let statement = client
+            .prepare("select * from users where id = ANY($1) and deleted_at is null")
+            .await?;
+
+        for row in client.query(&statement, &[&ids]).await? {
+            let user = User::from_row_ref(&row).map_err(|err| {
+                error!("Error getting parsing users. {}", err; "query" => "get_users_by_ids");
+                err
+            })?;
+
+            hashmap.insert(user.id, user);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BatchFn<Uuid, User> for UserBatcher {
+    type Error = AppError;
+
+    async fn load(&self, keys: &[Uuid]) -> HashMap<Uuid, Result<User, AppError>> {
+        info!("Loading users batch {:?}", keys);
+
+        let mut users_map: HashMap<Uuid, User> = HashMap::new();
+
+        let result: Result<(), AppError> = self
+            .get_users_by_ids(&mut users_map, keys.into())
+            .await;
+
+        keys.iter()
+            .map(move |id| {
+                let user = result.clone().and_then(|_| {
+                    users_map.remove(id).ok_or(AppError {
+                        cause: None,
+                        message: Some(format!("User with id {} not found", id)),
+                        error_type: AppErrorType::NotFoundError,
+                        field: None
+                    })
+                });
+                (id.clone(), user)
+            })
+            .collect::<HashMap<_, _>>()
+    }
+}
+
+fn invalid_credentials() -> AppError {
+    AppError {
+        message: Some("Invalid username, email or password.".to_string()),
+        cause: None,
+        error_type: AppErrorType::Unauthorized,
+        field: None
+    }
+}
+
+fn invalid_field(message: String) -> AppError {
+    AppError {
+        message: Some(message),
+        cause: None,
+        error_type: AppErrorType::InvalidField,
+        field: None
+    }
+}
+
+fn map_unique_violation(err: Error) -> AppError {
+    let field = match violated_constraint(&err) {
+        Some("users_username_lower_key") => Some("username"),
+        Some("users_email_lower_key") => Some("email"),
+        _ => None,
+    };
+
+    let err = AppError::from(err).with_message_for(AppErrorType::Conflict, "Username or email address already in use.".to_string());
+
+    match field {
+        Some(field) => err.with_field_for(AppErrorType::Conflict, field),
+        None => err,
+    }
+}
+
 impl UserRepository {
     pub fn new(pool: Arc<Pool>) -> UserRepository {
         UserRepository { pool }
@@ -25,7 +123,7 @@ impl UserRepository {
             err
         })?;
 
-        let statement = client.prepare("select * from users where id = $1").await?;
+        let statement = client.prepare("select * from users where id = $1 and deleted_at is null").await?;
 
         client
             .query(&statement, &[&id])
@@ -37,7 +135,8 @@ impl UserRepository {
             .ok_or(AppError {
                 cause: None,
                 message: Some(format!("User with id {} not found", id)),
-                error_type: AppErrorType::NotFoundError
+                error_type: AppErrorType::NotFoundError,
+                field: None
             })
     }
 
@@ -50,7 +149,7 @@ impl UserRepository {
             err
         })?;
 
-        let statement = client.prepare("select * from users").await?;
+        let statement = client.prepare("select * from users where deleted_at is null").await?;
 
         let users = client
             .query(&statement, &[])
@@ -75,44 +174,441 @@ impl UserRepository {
             err
         })?;
 
+        let username = normalize_username(&input.username).map_err(invalid_field)?;
+        let email = normalize_email(&input.email).map_err(invalid_field)?;
+
         let statement = client
-        .prepare("insert into users (username, email, password, bio, image) values ($1, $2, $3, $4, $5) returning *")
+        .prepare("insert into users (username, email, password, password_key_id, bio, image) values ($1, $2, $3, $4, $5, $6) returning *")
         .await?;
 
         let password_hash = hashing.hash(input.password).await?;
 
         let user = client
             .query(&statement, &[
-                &input.username,
-                &input.email,
-                &password_hash,
+                &username,
+                &email,
+                &password_hash.hash,
+                &password_hash.key_id,
                 &input.bio,
                 &input.image,
             ])
             .await
-            .map_err(|err: Error| {
-                let unique_error = err.code()
-                    .map(|code: &SqlState| code == &SqlState::UNIQUE_VIOLATION);
-                
-                    match unique_error {
-                        Some(true) => AppError {
-                            cause: Some(err.to_string()),
-                            message: Some("Username or email address already in use.".to_string()),
-                            error_type: AppErrorType::InvalidField
-                        },
-                        _ => AppError::from(err)
+            .map_err(map_unique_violation)?
+            .iter()
+            .map(|row| User::from_row_ref(row))
+            .collect::<Result<Vec<User>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                message: Some("Error creating User.".to_string()),
+                cause: None,
+                error_type: AppErrorType::DbError,
+                field: None
+            })?;
+
+        Ok(user)
+    }
+
+    pub async fn find_by_login(&self, username_or_email: &str) -> Result<Option<User>, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing users. {}", err; "query" => "find_by_login");
+            err
+        })?;
+
+        let statement = client
+            .prepare("select * from users where (lower(username) = lower($1) or lower(email) = lower($1)) and deleted_at is null")
+            .await?;
+
+        let user = client
+            .query(&statement, &[&normalize_login(username_or_email)])
+            .await?
+            .iter()
+            .map(|row| User::from_row_ref(row))
+            .collect::<Result<Vec<User>, _>>()?
+            .pop();
+
+        Ok(user)
+    }
+
+    /// Checks the password of a user found by `find_by_login`, if any
+    pub async fn authenticate(&self, user: Option<User>, password: String, hashing: Arc<HashingService>) -> Result<User, AppError> {
+        match user {
+            Some(user) => self.check_password(user, password, hashing).await,
+            None => {
+                // Pay for a hash anyway, response times shouldn't tell which accounts exist
+                hashing.verify_dummy(password).await?;
+                Err(invalid_credentials())
+            }
+        }
+    }
+
+    /// Verifies the user's password
+    pub async fn check_password(&self, user: User, password: String, hashing: Arc<HashingService>) -> Result<User, AppError> {
+        match hashing.verify(password.clone(), user.password.clone(), &user.password_key_id).await? {
+            false => Err(invalid_credentials()),
+            // Upgrade hashes made with older parameters or a retired key while we have the plain password
+            true if hashing.needs_rehash(&user.password, &user.password_key_id) => {
+                match self.update_password(user.id, password, hashing.clone()).await {
+                    Ok(user) => Ok(user),
+                    Err(err) => {
+                        error!("Error upgrading password hash. {}", err; "user_id" => user.id.to_string());
+                        Ok(user)
                     }
+                }
+            },
+            true => Ok(user),
+        }
+    }
+
+    pub async fn find_by_email(&self, email: &str) -> Result<Option<User>, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing users. {}", err; "query" => "find_by_email");
+            err
+        })?;
+
+        let statement = client
+            .prepare("select * from users where lower(email) = lower($1) and deleted_at is null")
+            .await?;
+
+        let user = client
+            .query(&statement, &[&normalize_login(email)])
+            .await?
+            .iter()
+            .map(|row| User::from_row_ref(row))
+            .collect::<Result<Vec<User>, _>>()?
+            .pop();
+
+        Ok(user)
+    }
+
+    pub async fn update_password(&self, id: Uuid, password: String, hashing: Arc<HashingService>) -> Result<User, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing users. {}", err; "query" => "update_password");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update users set password = $2, password_key_id = $3, updated_at = current_timestamp where id = $1 returning *")
+        .await?;
+
+        let password_hash = hashing.hash(password).await?;
+
+        client
+            .query(&statement, &[&id, &password_hash.hash, &password_hash.key_id])
+            .await?
+            .iter()
+            .map(|row| User::from_row_ref(row))
+            .collect::<Result<Vec<User>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                cause: None,
+                message: Some(format!("User with id {} not found", id)),
+                error_type: AppErrorType::NotFoundError,
+                field: None
+            })
+    }
+
+    /// Redeems a password reset token and stores the new password in one transaction,
+    /// so the token is only used up once the password has actually changed
+    pub async fn reset_password(&self, token_hash: &str, password_hash: PasswordHash) -> Result<User, AppError> {
+        let mut client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing users. {}", err; "query" => "reset_password");
+            err
+        })?;
+
+        let transaction = client.transaction().await?;
+
+        let consume = transaction
+        .prepare("update user_tokens set used_at = current_timestamp where token_hash = $1 and purpose = $2 and used_at is null and expires_at > current_timestamp returning user_id")
+        .await?;
+
+        let user_id: Uuid = transaction
+            .query(&consume, &[&token_hash, &TokenPurpose::PasswordReset.as_str()])
+            .await?
+            .pop()
+            .ok_or(AppError {
+                message: Some("Invalid or expired token".to_string()),
+                cause: None,
+                error_type: AppErrorType::InvalidField,
+                field: None
             })?
+            .try_get("user_id")?;
+
+        let statement = transaction
+        .prepare("update users set password = $2, password_key_id = $3, updated_at = current_timestamp where id = $1 returning *")
+        .await?;
+
+        let user = transaction
+            .query(&statement, &[&user_id, &password_hash.hash, &password_hash.key_id])
+            .await?
             .iter()
             .map(|row| User::from_row_ref(row))
             .collect::<Result<Vec<User>, _>>()?
             .pop()
             .ok_or(AppError {
-                message: Some("Error creating User.".to_string()),
                 cause: None,
-                error_type: AppErrorType::DbError
+                message: Some(format!("User with id {} not found", user_id)),
+                error_type: AppErrorType::NotFoundError,
+                field: None
             })?;
 
+        transaction.commit().await?;
+
         Ok(user)
     }
-}
\ No newline at end of file
+
+    /// Verifies the user's email, as long as it is still the address the token was sent to
+    pub async fn mark_email_verified(&self, id: Uuid, email: &str) -> Result<User, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing users. {}", err; "query" => "mark_email_verified");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update users set email_verified = true, updated_at = current_timestamp where id = $1 and lower(email) = lower($2) and deleted_at is null returning *")
+        .await?;
+
+        client
+            .query(&statement, &[&id, &email])
+            .await?
+            .iter()
+            .map(|row| User::from_row_ref(row))
+            .collect::<Result<Vec<User>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                cause: None,
+                message: Some("Invalid or expired token".to_string()),
+                error_type: AppErrorType::InvalidField,
+                field: None
+            })
+    }
+
+    /// Applies the supplied profile fields, a new email address has to be verified again
+    pub async fn update(&self, id: Uuid, input: UpdateUser) -> Result<User, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing users. {}", err; "query" => "update_user");
+            err
+        })?;
+
+        let username = input.username.as_ref().map(|username| normalize_username(username)).transpose().map_err(invalid_field)?;
+        let email = input.email.as_ref().map(|email| normalize_email(email)).transpose().map_err(invalid_field)?;
+
+        let statement = client
+        .prepare("update users set \
+            username = coalesce($2, username), \
+            email = coalesce($3, email), \
+            email_verified = case when $3::varchar is null or lower($3) = lower(email) then email_verified else false end, \
+            bio = case when $4::varchar is null then bio else nullif($4, '') end, \
+            image = case when $5::varchar is null then image else nullif($5, '') end, \
+            updated_at = current_timestamp \
+            where id = $1 and deleted_at is null returning *")
+        .await?;
+
+        client
+            .query(&statement, &[
+                &id,
+                &username,
+                &email,
+                &input.bio,
+                &input.image,
+            ])
+            .await
+            .map_err(map_unique_violation)?
+            .iter()
+            .map(|row| User::from_row_ref(row))
+            .collect::<Result<Vec<User>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                cause: None,
+                message: Some(format!("User with id {} not found", id)),
+                error_type: AppErrorType::NotFoundError,
+                field: None
+            })
+    }
+
+    pub async fn set_role(&self, id: Uuid, role: Role) -> Result<User, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing users. {}", err; "query" => "set_role");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update users set role = $2, updated_at = current_timestamp where id = $1 and deleted_at is null returning *")
+        .await?;
+
+        client
+            .query(&statement, &[&id, &role.as_str()])
+            .await?
+            .iter()
+            .map(|row| User::from_row_ref(row))
+            .collect::<Result<Vec<User>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                cause: None,
+                message: Some(format!("User with id {} not found", id)),
+                error_type: AppErrorType::NotFoundError,
+                field: None
+            })
+    }
+
+    /// Hides the user and everything they wrote until the account is restored or purged
+    pub async fn soft_delete(&self, id: Uuid, deleted_by: Uuid) -> Result<bool, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing users. {}", err; "query" => "soft_delete");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update users set deleted_at = current_timestamp, deleted_by = $2 where id = $1 and deleted_at is null")
+        .await?;
+
+        let deleted = client.execute(&statement, &[&id, &deleted_by]).await?;
+
+        Ok(deleted > 0)
+    }
+
+    /// A deleted account still within its `grace_period`, in seconds
+    pub async fn find_deleted_by_login(&self, username_or_email: &str, grace_period: i64) -> Result<Option<User>, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing users. {}", err; "query" => "find_deleted_by_login");
+            err
+        })?;
+
+        let statement = client
+            .prepare("select * from users where (lower(username) = lower($1) or lower(email) = lower($1)) \
+                and deleted_at > current_timestamp - make_interval(secs => $2)")
+            .await?;
+
+        let user = client
+            .query(&statement, &[&normalize_login(username_or_email), &(grace_period as f64)])
+            .await?
+            .iter()
+            .map(|row| User::from_row_ref(row))
+            .collect::<Result<Vec<User>, _>>()?
+            .pop();
+
+        Ok(user)
+    }
+
+    /// Restores an account found by `find_deleted_by_login`, if its owner deleted it and the password matches
+    pub async fn restore_account(
+        &self,
+        user: Option<User>,
+        password: String,
+        grace_period: i64,
+        hashing: Arc<HashingService>,
+    ) -> Result<User, AppError> {
+        let user = match user {
+            Some(user) => self.check_password(user, password, hashing).await?,
+            None => {
+                hashing.verify_dummy(password).await?;
+                return Err(invalid_credentials());
+            }
+        };
+
+        if user.deleted_by != Some(user.id) {
+            return Err(AppError {
+                cause: None,
+                message: Some("This account was deleted by an administrator".to_string()),
+                error_type: AppErrorType::Forbidden,
+                field: None
+            });
+        }
+
+        self.restore(user.id, grace_period).await
+    }
+
+    /// Restores an account deleted less than `grace_period` seconds ago, whoever deleted it.
+    /// Owners go through `restore_account`, which only lets them undo their own deletion.
+    pub async fn restore(&self, id: Uuid, grace_period: i64) -> Result<User, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing users. {}", err; "query" => "restore");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update users set deleted_at = null, deleted_by = null, updated_at = current_timestamp \
+            where id = $1 and deleted_at > current_timestamp - make_interval(secs => $2) returning *")
+        .await?;
+
+        client
+            .query(&statement, &[&id, &(grace_period as f64)])
+            .await?
+            .iter()
+            .map(|row| User::from_row_ref(row))
+            .collect::<Result<Vec<User>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                cause: None,
+                message: Some(format!("User with id {} not found", id)),
+                error_type: AppErrorType::NotFoundError,
+                field: None
+            })
+    }
+
+    /// Permanently removes accounts deleted longer than `grace_period` seconds ago
+    /// Other users' replies to purged comments become top level comments, as in `CommentRepository::delete`
+    pub async fn purge_deleted(&self, grace_period: i64) -> Result<u64, AppError> {
+        let mut client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing users. {}", err; "query" => "purge_deleted");
+            err
+        })?;
+
+        let transaction = client.transaction().await?;
+
+        let promote = transaction
+        .prepare("with recursive purged as (select id from users where deleted_at < current_timestamp - make_interval(secs => $1)), \
+            thread (id, depth) as (\
+                select comments.id, 0 from comments join comments parent on parent.id = comments.parent_id \
+                where parent.author_id in (select id from purged) and comments.author_id not in (select id from purged) \
+                union all \
+                select comments.id, thread.depth + 1 from comments join thread on comments.parent_id = thread.id \
+                where comments.author_id not in (select id from purged)) \
+            update comments set depth = thread.depth from thread where comments.id = thread.id")
+        .await?;
+
+        transaction.execute(&promote, &[&(grace_period as f64)]).await?;
+
+        let statement = transaction
+        .prepare("delete from users where deleted_at < current_timestamp - make_interval(secs => $1)")
+        .await?;
+
+        let purged = transaction.execute(&statement, &[&(grace_period as f64)]).await?;
+
+        transaction.commit().await?;
+
+        Ok(purged)
+    }
+}
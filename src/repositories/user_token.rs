@@ -0,0 +1,108 @@
+use deadpool_postgres::{Client, Pool};
+use slog_scope::error;
+use uuid::Uuid;
+use std::sync::Arc;
+use tokio_pg_mapper::FromTokioPostgresRow;
+
+use crate::{errors::{AppError, AppErrorType}, models::user_token::{TokenPurpose, UserToken}};
+
+pub struct UserTokenRepository {
+    pool: Arc<Pool>,
+}
+
+impl UserTokenRepository {
+    pub fn new(pool: Arc<Pool>) -> UserTokenRepository {
+        UserTokenRepository { pool }
+    }
+
+    /// Stores a new token for the user, invalidating any outstanding token with the same purpose.
+    /// Email verification tokens are bound to the address they are sent to.
+    pub async fn issue(
+        &self,
+        user_id: Uuid,
+        purpose: TokenPurpose,
+        token_hash: &str,
+        email: Option<&str>,
+        ttl: i64,
+    ) -> Result<UserToken, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing user tokens. {}", err; "query" => "issue");
+            err
+        })?;
+
+        let invalidate = client
+        .prepare("update user_tokens set used_at = current_timestamp where user_id = $1 and purpose = $2 and used_at is null")
+        .await?;
+
+        client.execute(&invalidate, &[&user_id, &purpose.as_str()]).await?;
+
+        let statement = client
+        .prepare("insert into user_tokens (user_id, purpose, token_hash, email, expires_at) values ($1, $2, $3, $4, current_timestamp + make_interval(secs => $5)) returning *")
+        .await?;
+
+        client
+            .query(&statement, &[&user_id, &purpose.as_str(), &token_hash, &email, &(ttl as f64)])
+            .await?
+            .iter()
+            .map(|row| UserToken::from_row_ref(row))
+            .collect::<Result<Vec<UserToken>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                message: Some("Error creating token.".to_string()),
+                cause: None,
+                error_type: AppErrorType::DbError,
+                field: None
+            })
+    }
+
+    /// Marks a valid token as used, so it can't be redeemed twice
+    pub async fn consume(&self, token_hash: &str, purpose: TokenPurpose) -> Result<UserToken, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing user tokens. {}", err; "query" => "consume");
+            err
+        })?;
+
+        let statement = client
+        .prepare("update user_tokens set used_at = current_timestamp where token_hash = $1 and purpose = $2 and used_at is null and expires_at > current_timestamp returning *")
+        .await?;
+
+        client
+            .query(&statement, &[&token_hash, &purpose.as_str()])
+            .await?
+            .iter()
+            .map(|row| UserToken::from_row_ref(row))
+            .collect::<Result<Vec<UserToken>, _>>()?
+            .pop()
+            .ok_or(AppError {
+                message: Some("Invalid or expired token".to_string()),
+                cause: None,
+                error_type: AppErrorType::InvalidField,
+                field: None
+            })
+    }
+
+    /// Deletes the user's outstanding tokens with the purpose
+    pub async fn revoke(&self, user_id: Uuid, purpose: TokenPurpose) -> Result<u64, AppError> {
+        let client: Client = self.pool
+        .get()
+        .await
+        .map_err(|err| {
+            error!("Error getting parsing user tokens. {}", err; "query" => "revoke");
+            err
+        })?;
+
+        let statement = client
+        .prepare("delete from user_tokens where user_id = $1 and purpose = $2 and used_at is null")
+        .await?;
+
+        let revoked = client.execute(&statement, &[&user_id, &purpose.as_str()]).await?;
+
+        Ok(revoked)
+    }
+}
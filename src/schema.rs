@@ -1,3 +1,17 @@
+table! {
+    access_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        name -> Varchar,
+        scopes -> Array<Varchar>,
+        token_hash -> Varchar,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+        expires_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     comments (id) {
         id -> Uuid,
@@ -6,6 +20,33 @@ table! {
         body -> Text,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        parent_id -> Nullable<Uuid>,
+        depth -> Int4,
+    }
+}
+
+table! {
+    follows (follower_id, followee_id) {
+        follower_id -> Uuid,
+        followee_id -> Uuid,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    login_attempts (key) {
+        key -> Varchar,
+        failures -> Int4,
+        last_failed_at -> Timestamp,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    post_slug_history (slug) {
+        slug -> Varchar,
+        post_id -> Uuid,
+        created_at -> Timestamp,
     }
 }
 
@@ -22,6 +63,20 @@ table! {
     }
 }
 
+table! {
+    sessions (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        refresh_token_hash -> Varchar,
+        user_agent -> Nullable<Varchar>,
+        ip -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     users (id) {
         id -> Uuid,
@@ -32,15 +87,43 @@ table! {
         image -> Nullable<Varchar>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        email_verified -> Bool,
+        password_key_id -> Varchar,
+        role -> Varchar,
+        deleted_at -> Nullable<Timestamp>,
+        deleted_by -> Nullable<Uuid>,
+    }
+}
+
+table! {
+    user_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        purpose -> Varchar,
+        token_hash -> Varchar,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        email -> Nullable<Varchar>,
     }
 }
 
+joinable!(access_tokens -> users (user_id));
 joinable!(comments -> posts (post_id));
 joinable!(comments -> users (author_id));
+joinable!(post_slug_history -> posts (post_id));
 joinable!(posts -> users (author_id));
+joinable!(sessions -> users (user_id));
+joinable!(user_tokens -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    access_tokens,
     comments,
+    follows,
+    login_attempts,
+    post_slug_history,
     posts,
+    sessions,
+    user_tokens,
     users,
 );
@@ -0,0 +1,325 @@
+use serde::Serialize;
+
+use crate::{
+    errors::{AppError, AppErrorType},
+    models::{
+        access_token::CreateAccessToken,
+        comment::{CreateComment, UpdateComment},
+        post::{is_valid_slug, CreatePost, UpdatePost, SLUG_MAX_LENGTH},
+        user::{normalize_email, normalize_username, CreateUser, UpdateUser},
+    },
+};
+
+pub const PASSWORD_MIN_LENGTH: usize = 8;
+pub const PASSWORD_MAX_LENGTH: usize = 128;
+pub const BIO_MAX_LENGTH: usize = 1000;
+pub const IMAGE_MAX_LENGTH: usize = 2048;
+pub const TITLE_MAX_LENGTH: usize = 200;
+pub const DESCRIPTION_MAX_LENGTH: usize = 500;
+pub const BODY_MAX_LENGTH: usize = 100_000;
+pub const COMMENT_MAX_LENGTH: usize = 10_000;
+pub const ACCESS_TOKEN_NAME_MAX_LENGTH: usize = 100;
+pub const ACCESS_TOKEN_MAX_DAYS: i32 = 365;
+
+/// One problem with one input field
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Violation {
+    pub field: String,
+    /// Stable identifier clients can match on, e.g. `required` or `too_long`
+    pub code: String,
+    pub message: String,
+}
+
+/// Collects every violation in an input instead of stopping at the first one
+#[derive(Default)]
+pub struct Validator {
+    violations: Vec<Violation>,
+}
+
+impl Validator {
+    pub fn new() -> Validator {
+        Validator::default()
+    }
+
+    pub fn violation(&mut self, field: &str, code: &str, message: String) -> &mut Validator {
+        self.violations.push(Violation {
+            field: field.to_string(),
+            code: code.to_string(),
+            message,
+        });
+        self
+    }
+
+    pub fn required(&mut self, field: &str, value: &str) -> &mut Validator {
+        if value.trim().is_empty() {
+            self.violation(field, "required", format!("{} can't be blank", field));
+        }
+        self
+    }
+
+    pub fn max_length(&mut self, field: &str, value: &str, max: usize) -> &mut Validator {
+        if value.chars().count() > max {
+            self.violation(field, "too_long", format!("{} can't be longer than {} characters", field, max));
+        }
+        self
+    }
+
+    pub fn range(&mut self, field: &str, value: i32, min: i32, max: i32) -> &mut Validator {
+        if value < min || value > max {
+            self.violation(field, "out_of_range", format!("{} must be between {} and {}", field, min, max));
+        }
+        self
+    }
+
+    pub fn password(&mut self, field: &str, value: &str) -> &mut Validator {
+        if value.chars().count() < PASSWORD_MIN_LENGTH {
+            self.violation(
+                field,
+                "too_short",
+                format!("{} must be at least {} characters long", field, PASSWORD_MIN_LENGTH),
+            );
+        }
+        self.max_length(field, value, PASSWORD_MAX_LENGTH)
+    }
+
+    pub fn username(&mut self, field: &str, value: &str) -> &mut Validator {
+        if let Err(message) = normalize_username(value) {
+            self.violation(field, "invalid", message);
+        }
+        self
+    }
+
+    pub fn email(&mut self, field: &str, value: &str) -> &mut Validator {
+        if let Err(message) = normalize_email(value) {
+            self.violation(field, "invalid", message);
+        }
+        self
+    }
+
+    pub fn url(&mut self, field: &str, value: &str) -> &mut Validator {
+        if !(value.starts_with("https://") || value.starts_with("http://")) {
+            self.violation(field, "invalid", format!("{} must be an http or https URL", field));
+        }
+        self.max_length(field, value, IMAGE_MAX_LENGTH)
+    }
+
+    /// Lowercase letters, numbers and single dashes between them, the same rules generated slugs follow
+    pub fn slug(&mut self, field: &str, value: &str) -> &mut Validator {
+        if value.chars().count() > SLUG_MAX_LENGTH {
+            return self.max_length(field, value, SLUG_MAX_LENGTH);
+        }
+
+        if !is_valid_slug(value) {
+            self.violation(
+                field,
+                "invalid",
+                format!("{} can only contain lowercase letters, numbers and single dashes", field),
+            );
+        }
+        self
+    }
+
+    /// A ValidationError with every violation found, if any
+    pub fn finish(&mut self) -> Result<(), AppError> {
+        match self.violations.is_empty() {
+            true => Ok(()),
+            false => Err(AppError {
+                message: None,
+                cause: None,
+                error_type: AppErrorType::ValidationError(self.violations.drain(..).collect()),
+                field: None,
+            }),
+        }
+    }
+}
+
+/// GraphQL inputs that check their own fields before reaching a repository
+pub trait Validate {
+    fn validate(&self, validator: &mut Validator);
+}
+
+pub fn validate<T: Validate>(input: &T) -> Result<(), AppError> {
+    let mut validator = Validator::new();
+    input.validate(&mut validator);
+    validator.finish()
+}
+
+impl Validate for CreateUser {
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .username("username", &self.username)
+            .email("email", &self.email)
+            .password("password", &self.password);
+
+        if let Some(bio) = &self.bio {
+            validator.max_length("bio", bio, BIO_MAX_LENGTH);
+        }
+        if let Some(image) = &self.image {
+            validator.url("image", image);
+        }
+    }
+}
+
+impl Validate for UpdateUser {
+    fn validate(&self, validator: &mut Validator) {
+        if let Some(username) = &self.username {
+            validator.username("username", username);
+        }
+        if let Some(email) = &self.email {
+            validator.email("email", email);
+        }
+        // Empty strings clear the bio and image
+        if let Some(bio) = self.bio.as_ref().filter(|bio| !bio.is_empty()) {
+            validator.max_length("bio", bio, BIO_MAX_LENGTH);
+        }
+        if let Some(image) = self.image.as_ref().filter(|image| !image.is_empty()) {
+            validator.url("image", image);
+        }
+    }
+}
+
+impl Validate for CreatePost {
+    fn validate(&self, validator: &mut Validator) {
+        if let Some(slug) = &self.slug {
+            validator.slug("slug", slug);
+        }
+
+        validator
+            .required("title", &self.title)
+            .max_length("title", &self.title, TITLE_MAX_LENGTH)
+            .required("description", &self.description)
+            .max_length("description", &self.description, DESCRIPTION_MAX_LENGTH)
+            .required("body", &self.body)
+            .max_length("body", &self.body, BODY_MAX_LENGTH);
+    }
+}
+
+impl Validate for UpdatePost {
+    fn validate(&self, validator: &mut Validator) {
+        if let Some(slug) = &self.slug {
+            validator.slug("slug", slug);
+        }
+        if let Some(title) = &self.title {
+            validator.required("title", title).max_length("title", title, TITLE_MAX_LENGTH);
+        }
+        if let Some(description) = &self.description {
+            validator
+                .required("description", description)
+                .max_length("description", description, DESCRIPTION_MAX_LENGTH);
+        }
+        if let Some(body) = &self.body {
+            validator.required("body", body).max_length("body", body, BODY_MAX_LENGTH);
+        }
+    }
+}
+
+impl Validate for CreateComment {
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .required("body", &self.body)
+            .max_length("body", &self.body, COMMENT_MAX_LENGTH);
+    }
+}
+
+impl Validate for UpdateComment {
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .required("body", &self.body)
+            .max_length("body", &self.body, COMMENT_MAX_LENGTH);
+    }
+}
+
+impl Validate for CreateAccessToken {
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .required("name", &self.name)
+            .max_length("name", &self.name, ACCESS_TOKEN_NAME_MAX_LENGTH);
+
+        if let Some(days) = self.expires_in_days {
+            validator.range("expiresInDays", days, 1, ACCESS_TOKEN_MAX_DAYS);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{validate, Violation};
+    use crate::{
+        errors::AppErrorType,
+        models::{access_token::CreateAccessToken, post::CreatePost, user::UpdateUser},
+    };
+
+    fn violations(input: &CreatePost) -> Vec<Violation> {
+        match validate(input) {
+            Ok(()) => vec![],
+            Err(err) => match err.error_type {
+                AppErrorType::ValidationError(violations) => violations,
+                _ => panic!("Validation should fail with a ValidationError"),
+            },
+        }
+    }
+
+    #[test]
+    fn test_valid_input() {
+        let post = CreatePost {
+            slug: Some("hello-world".to_string()),
+            title: "Hello".to_string(),
+            description: "First post".to_string(),
+            body: "Hello, world".to_string(),
+        };
+
+        assert!(violations(&post).is_empty(), "Valid input should pass");
+    }
+
+    #[test]
+    fn test_collects_every_violation() {
+        let post = CreatePost {
+            slug: Some("Hello World".to_string()),
+            title: " ".to_string(),
+            description: "First post".to_string(),
+            body: "a".repeat(100_001),
+        };
+
+        let fields: Vec<(String, String)> = violations(&post)
+            .into_iter()
+            .map(|violation| (violation.field, violation.code))
+            .collect();
+
+        assert_eq!(
+            fields,
+            vec![
+                ("slug".to_string(), "invalid".to_string()),
+                ("title".to_string(), "required".to_string()),
+                ("body".to_string(), "too_long".to_string()),
+            ],
+            "Every violation should be reported"
+        );
+    }
+
+    #[test]
+    fn test_empty_bio_and_image_clear() {
+        let input = UpdateUser {
+            username: None,
+            email: None,
+            bio: Some("".to_string()),
+            image: Some("".to_string()),
+        };
+
+        assert!(validate(&input).is_ok(), "Empty bio and image should be accepted to clear them");
+    }
+
+    #[test]
+    fn test_access_token_lifetime() {
+        let input = |expires_in_days| CreateAccessToken {
+            name: "deploy".to_string(),
+            scopes: vec![],
+            expires_in_days,
+        };
+
+        assert!(validate(&input(None)).is_ok(), "Tokens without an expiration should pass");
+        assert!(validate(&input(Some(30))).is_ok(), "Tokens valid for a month should pass");
+        assert!(validate(&input(Some(0))).is_err(), "Tokens must be valid for at least a day");
+        assert!(validate(&input(Some(std::i32::MAX))).is_err(), "Lifetimes that overflow should be rejected");
+    }
+}